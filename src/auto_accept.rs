@@ -0,0 +1,139 @@
+use crate::config::load_config;
+use crate::search::SearchResult;
+use crate::text_extraction::Preprocessing;
+
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use std::collections::HashSet;
+
+lazy_static! {
+    pub(crate) static ref AUTO_ACCEPT: AutoAccept = load_config("./auto_accept.json");
+}
+
+/// When a scanned card is confident enough to be added without a tap
+/// Set in `auto_accept.json`, it's off unless that turns it on
+#[derive(Deserialize)]
+#[serde(default)]
+pub(crate) struct AutoAccept {
+    /// Whether new sessions start with it on, the UI can turn it on and off
+    pub(crate) enabled: bool,
+    /// How far the top candidate's score has to beat the runner-up's
+    pub(crate) margin: f64,
+    /// How many searches in a row the top candidate has to stay on top by `margin`
+    pub(crate) stable_frames: usize,
+    /// How long an accepted card can be undone from the UI
+    pub(crate) undo_seconds: u64,
+}
+
+impl Default for AutoAccept {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            margin: 0.05,
+            stable_frames: 3,
+            undo_seconds: 5,
+        }
+    }
+}
+
+/// How many of a search's candidates a reject steps through before the card is read again, further down
+/// the list the scores are mostly noise
+const CANDIDATES: usize = 5;
+
+/// What we've worked out about the physical card in frame, it starts over when a new card shows up
+#[derive(Default)]
+pub(crate) struct Tracker {
+    /// The last search's best candidates, top first, without the ones rejected since
+    candidates: Vec<SearchResult>,
+    /// How many searches in a row the top candidate has led by the margin, 0 if it didn't last time
+    streak: usize,
+    /// Oracle ids the user rejected for this card, searches leave them out
+    rejected: HashSet<String>,
+    /// How the card is cleaned up for OCR, changes each time the candidates run out
+    preprocessing: Preprocessing,
+    /// Oracle ids from an auto accept that was undone after this card left, for the next card to show up, which is
+    /// most likely the same one put back
+    carried: HashSet<String>,
+}
+
+impl Tracker {
+    /// Take in another search's results, returning whether the top candidate changed
+    pub(crate) fn observe(&mut self, results: &[SearchResult]) -> bool {
+        let leads = match results {
+            [first, second, ..] => first.score - second.score >= AUTO_ACCEPT.margin,
+            [_] => true,
+            [] => false,
+        };
+        let changed = results.first().map(|result| &result.oracle_id)
+            != self.candidates.first().map(|result| &result.oracle_id);
+
+        self.streak = match (leads, changed) {
+            (false, _) => 0,
+            (true, true) => 1,
+            (true, false) => self.streak + 1,
+        };
+        self.candidates = results.iter().take(CANDIDATES).cloned().collect();
+        changed
+    }
+
+    pub(crate) fn is_stable(&self) -> bool {
+        self.streak >= AUTO_ACCEPT.stable_frames
+    }
+
+    /// The printing to add now that the card has left the frame, if it was stable, along with the oracle ids to leave
+    /// out if that's undone: its own and any rejected before. It's only handed out once
+    pub(crate) fn take_accepted(&mut self) -> Option<(String, HashSet<String>)> {
+        if !self.is_stable() {
+            return None;
+        }
+        self.streak = 0;
+        let top = std::mem::take(&mut self.candidates).into_iter().next()?;
+        let mut rejected = self.rejected.clone();
+        rejected.insert(top.oracle_id);
+        Some((top.id, rejected))
+    }
+
+    /// Leave these oracle ids out of this card's searches from now on, as if each had been rejected
+    pub(crate) fn blacklist(&mut self, ids: HashSet<String>) {
+        self.candidates
+            .retain(|candidate| !ids.contains(&candidate.oracle_id));
+        self.rejected.extend(ids);
+        self.streak = 0;
+    }
+
+    /// Hold on to oracle ids for whichever card is tracked next, see `take_carried`
+    pub(crate) fn carry(&mut self, ids: HashSet<String>) {
+        self.carried.extend(ids);
+    }
+
+    pub(crate) fn take_carried(&mut self) -> HashSet<String> {
+        std::mem::take(&mut self.carried)
+    }
+
+    /// The user says the top candidate is wrong, so leave it out of this card's searches from now on
+    /// Returns whether there are other candidates left, if not the card needs reading again
+    pub(crate) fn reject(&mut self) -> bool {
+        if !self.candidates.is_empty() {
+            let rejected = self.candidates.remove(0);
+            self.rejected.insert(rejected.oracle_id);
+        }
+        self.streak = 0;
+        if self.candidates.is_empty() {
+            self.preprocessing = self.preprocessing.next();
+            return false;
+        }
+        true
+    }
+
+    pub(crate) fn candidates(&self) -> &[SearchResult] {
+        &self.candidates
+    }
+
+    pub(crate) fn rejected(&self) -> &HashSet<String> {
+        &self.rejected
+    }
+
+    pub(crate) fn preprocessing(&self) -> Preprocessing {
+        self.preprocessing
+    }
+}
@@ -1,12 +1,18 @@
+use crate::auto_accept::Tracker;
+use crate::frame_quality::{self, FrameWindow, Measurement};
+
 use anyhow::Result;
 use opencv::{
-    core::{BorderTypes, DecompTypes, Mat, Point, Point2f, Scalar, Size, Vector},
+    core::{
+        count_non_zero, mean_std_dev, no_array, BorderTypes, DecompTypes, Mat, MatTraitConst,
+        Point, Point2f, Scalar, Size, Vector, CV_64F,
+    },
     imgproc::{
-        contour_area, get_perspective_transform, line, warp_perspective, InterpolationFlags,
-        LineTypes,
+        contour_area, cvt_color, get_perspective_transform, laplacian, line, threshold,
+        warp_perspective, InterpolationFlags, LineTypes, COLOR_BGR2GRAY, THRESH_BINARY,
     },
 };
-use std::time::SystemTime;
+use std::{collections::HashSet, time::SystemTime};
 
 pub(crate) fn distance_formula(x1: i32, y1: i32, x2: i32, y2: i32) -> f64 {
     (((x2 - x1) as f64).powi(2) + ((y2 - y1) as f64).powi(2)).sqrt()
@@ -22,8 +28,21 @@ pub struct Card {
     pub radius: f64,
     pub area: f64,
     pub contour: Vector<Point>,
+    pub(crate) tracker: Tracker,
+    /// How far the corners moved since the last frame, see `frame_quality::corner_motion`
+    pub(crate) motion: f64,
+    /// Unwarped images of the card waiting for the sharpest to be read
+    pub(crate) window: FrameWindow<Mat>,
 }
 
+/// Gray level from which a pixel counts as a blown out highlight
+const GLARE_LEVEL: f64 = 250.0;
+/// Share of its area the card's outline can grow or shrink by between sightings and still be the same card
+const SWAP_AREA: f64 = 0.25;
+/// How far the corners can jump between sightings, as a share of the radius, and still be the same card
+/// A card being held moves a few percent at most, see `FrameQuality::max_motion`
+const SWAP_MOTION: f64 = 0.5;
+
 impl Card {
     pub fn new(rect: Vec<[i32; 2]>, contour: Vector<Point>) -> Self {
         let x = rect.iter().map(|[x, _]| x).sum::<i32>() / rect.len() as i32;
@@ -50,11 +69,24 @@ impl Card {
             radius,
             area,
             contour,
+            tracker: Tracker::default(),
+            motion: 0.0,
+            window: FrameWindow::default(),
         }
     }
 
-    pub fn update(&mut self, new_card: Card) {
-        if self.alive && distance_formula(new_card.x, new_card.y, self.x, self.y) < self.radius {
+    /// Follow the card into a new frame, or start over when `new_card` is a different one. That's one somewhere else,
+    /// or one swapped into the same spot, which shows as the outline changing size or the corners jumping
+    /// Either way the old card has left, so its stable match (see `Tracker::take_accepted`) is handed back
+    pub fn update(&mut self, new_card: Card) -> Option<(String, HashSet<String>)> {
+        let motion = frame_quality::corner_motion(&self.rect, &new_card.rect, self.radius);
+        let resized = (new_card.area - self.area).abs() > self.area * SWAP_AREA;
+        if self.alive
+            && distance_formula(new_card.x, new_card.y, self.x, self.y) < self.radius
+            && motion < SWAP_MOTION
+            && !resized
+        {
+            self.motion = motion;
             self.rect = new_card.rect;
             self.last_seen = new_card.last_seen;
             self.x = new_card.x;
@@ -62,8 +94,18 @@ impl Card {
             self.radius = new_card.radius;
             self.area = new_card.area;
             self.contour = new_card.contour;
+            None
         } else {
+            // A card that's no longer alive either already left (and was handed out then) or was tapped
+            let accepted = if self.alive {
+                self.tracker.take_accepted()
+            } else {
+                None
+            };
+            let carried = self.tracker.take_carried();
             *self = new_card;
+            self.tracker.blacklist(carried);
+            accepted
         }
     }
 
@@ -173,6 +215,38 @@ impl Card {
         )?;
         Ok(result)
     }
+
+    /// How usable `card_image` (from `get_unwarped`) is for OCR
+    pub(crate) fn measure(&self, card_image: &Mat) -> Result<Measurement> {
+        let mut gray = Mat::default();
+        cvt_color(card_image, &mut gray, COLOR_BGR2GRAY, 0)?;
+
+        // Sharp text has lots of strong edges, so the Laplacian varies a lot more than on a blurry frame
+        let mut edges = Mat::default();
+        laplacian(
+            &gray,
+            &mut edges,
+            CV_64F,
+            1,
+            1.0,
+            0.0,
+            BorderTypes::BORDER_DEFAULT as i32,
+        )?;
+        let mut mean = Mat::default();
+        let mut deviation = Mat::default();
+        mean_std_dev(&edges, &mut mean, &mut deviation, &no_array())?;
+        let sharpness = deviation.at::<f64>(0)?.powi(2);
+
+        let mut highlights = Mat::default();
+        threshold(&gray, &mut highlights, GLARE_LEVEL, 255.0, THRESH_BINARY)?;
+        let glare = count_non_zero(&highlights)? as f64 / gray.total().max(1) as f64;
+
+        Ok(Measurement {
+            sharpness,
+            glare,
+            motion: self.motion,
+        })
+    }
 }
 
 impl Default for Card {
@@ -187,6 +261,9 @@ impl Default for Card {
             radius: 0.0,
             area: 0.0,
             contour: Vector::default(),
+            tracker: Tracker::default(),
+            motion: 0.0,
+            window: FrameWindow::default(),
         }
     }
 }
@@ -1,179 +1,1871 @@
+use crate::auto_accept::AUTO_ACCEPT;
+use crate::card_store::{self, PrintingKey};
+use crate::pricing::{Price, PRICING};
+
+use anyhow::{bail, Result};
+use chrono::{DateTime, Local, Utc};
+use rusqlite::{params, params_from_iter, types::Value, Connection, OpenFlags, OptionalExtension};
+use scryers::card::Card;
 use serde::{Deserialize, Serialize};
 
 use std::{
     collections::{HashMap, HashSet},
     fs::{self, File},
-    io::{self, Write},
+    io::Read,
+    sync::{
+        atomic::{self, AtomicUsize},
+        Arc, Mutex,
+    },
+    time::{Instant, SystemTime, UNIX_EPOCH},
 };
 
 lazy_static::lazy_static! {
-    pub(crate) static ref CARD_DATABASE: std::sync::Mutex<CardDatabase> = std::sync::Mutex::new(CardDatabase::load().unwrap_or_default());
+    static ref COLLECTIONS: Mutex<HashMap<String, Arc<Mutex<CardDatabase>>>> = Mutex::new(HashMap::new());
 }
 
-#[derive(Serialize, Deserialize)]
+/// The collection everything used to go into, it keeps its old file name
+pub(crate) const DEFAULT_COLLECTION: &str = "default";
+const DATABASE_PATH: &str = "./collection.sqlite";
+/// Every other collection is `<name>.sqlite` in here
+const COLLECTION_DIRECTORY: &str = "./collections";
+const BACKUP_DIRECTORY: &str = "./backups";
+/// How many timestamped backups to keep around, the oldest get deleted first
+const BACKUPS_KEPT: usize = 10;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
 pub(crate) enum ChangeType {
     Inc,
     Dec,
     Set,
+    /// Moved to or from another collection, see `HistoryEntry::counterpart`
+    Transfer,
+    /// Added by importing another tool's export
+    Import,
 }
 
-#[derive(Serialize, Deserialize)]
+impl ChangeType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ChangeType::Inc => "Inc",
+            ChangeType::Dec => "Dec",
+            ChangeType::Set => "Set",
+            ChangeType::Transfer => "Transfer",
+            ChangeType::Import => "Import",
+        }
+    }
+
+    fn from_str(change_type: &str) -> Self {
+        match change_type {
+            "Inc" => ChangeType::Inc,
+            "Dec" => ChangeType::Dec,
+            "Transfer" => ChangeType::Transfer,
+            "Import" => ChangeType::Import,
+            _ => ChangeType::Set,
+        }
+    }
+}
+
+/// Scryfall's names for the finishes a printing comes in
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Hash, Default, Debug)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Finish {
+    #[default]
+    NonFoil,
+    Foil,
+    Etched,
+}
+
+impl Finish {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Finish::NonFoil => "nonfoil",
+            Finish::Foil => "foil",
+            Finish::Etched => "etched",
+        }
+    }
+
+    pub(crate) fn from_str(finish: &str) -> Option<Self> {
+        match finish {
+            "nonfoil" => Some(Finish::NonFoil),
+            "foil" => Some(Finish::Foil),
+            "etched" => Some(Finish::Etched),
+            _ => None,
+        }
+    }
+}
+
+/// The usual grading scale, most sites use these same abbreviations
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Hash, Default, Debug)]
+pub(crate) enum Condition {
+    #[default]
+    NearMint,
+    LightlyPlayed,
+    ModeratelyPlayed,
+    HeavilyPlayed,
+    Damaged,
+}
+
+impl Condition {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Condition::NearMint => "NM",
+            Condition::LightlyPlayed => "LP",
+            Condition::ModeratelyPlayed => "MP",
+            Condition::HeavilyPlayed => "HP",
+            Condition::Damaged => "DMG",
+        }
+    }
+
+    pub(crate) fn from_str(condition: &str) -> Option<Self> {
+        match condition.to_uppercase().as_str() {
+            "NM" => Some(Condition::NearMint),
+            "LP" => Some(Condition::LightlyPlayed),
+            "MP" => Some(Condition::ModeratelyPlayed),
+            "HP" => Some(Condition::HeavilyPlayed),
+            "DMG" => Some(Condition::Damaged),
+            _ => None,
+        }
+    }
+}
+
+/// Everything besides the printing that tells two copies of a card apart
+#[derive(Serialize, Clone, PartialEq, Eq, Hash, Debug)]
+pub(crate) struct Variant {
+    pub(crate) finish: Finish,
+    pub(crate) condition: Condition,
+    /// Scryfall language code, `en`, `ja`, `zhs`...
+    pub(crate) language: String,
+    pub(crate) signed: bool,
+    pub(crate) altered: bool,
+}
+
+impl Default for Variant {
+    fn default() -> Self {
+        Self::new(Finish::NonFoil)
+    }
+}
+
+impl Variant {
+    /// A near mint, unmodified English copy
+    pub(crate) fn new(finish: Finish) -> Self {
+        Self {
+            finish,
+            condition: Condition::NearMint,
+            language: "en".to_owned(),
+            signed: false,
+            altered: false,
+        }
+    }
+
+    pub(crate) fn to_json(&self) -> String {
+        format!(
+            r#""finish": "{}", "condition": "{}", "language": "{}", "signed": {}, "altered": {}"#,
+            self.finish.as_str(),
+            self.condition.as_str(),
+            self.language,
+            self.signed,
+            self.altered
+        )
+    }
+}
+
+#[derive(Serialize)]
 pub(crate) struct HistoryEntry {
-    pub(crate) file_name: String,
+    pub(crate) key: PrintingKey,
+    pub(crate) change_type: ChangeType,
+    pub(crate) previous_value: usize,
+    pub(crate) updated_value: usize,
+    pub(crate) variant: Variant,
+    /// Unix timestamp, older entries don't have one
+    pub(crate) created_at: Option<i64>,
+    pub(crate) session_id: Option<String>,
+    pub(crate) client_id: Option<String>,
+    /// The other collection, for transfers
+    pub(crate) counterpart: Option<String>,
+    /// Shared by both sides of a transfer, so they're undone and redone together
+    pub(crate) transfer_id: Option<String>,
+}
+
+/// Columns to select to build a `HistoryEntry` with `history_entry_from_row`
+const HISTORY_COLUMNS: &str = "scryfall_id, face, change_type, previous_value, updated_value, finish, condition, language, signed, altered, created_at, session_id, client_id, counterpart, transfer_id";
+
+/// Columns that make up a `Variant`, in the order `variant_from_row` expects them
+const VARIANT_COLUMNS: &str = "finish, condition, language, signed, altered";
+
+/// Matches one variant of a printing, with `variant_params` as ?1 to ?7
+const VARIANT_MATCH: &str =
+    "scryfall_id = ?1 AND face = ?2 AND finish = ?3 AND condition = ?4 AND language = ?5 AND signed = ?6 AND altered = ?7";
+
+fn variant_params(key: &PrintingKey, variant: &Variant) -> Vec<Value> {
+    vec![
+        Value::from(key.scryfall_id.clone()),
+        Value::from(key.face as i64),
+        Value::from(variant.finish.as_str().to_owned()),
+        Value::from(variant.condition.as_str().to_owned()),
+        Value::from(variant.language.clone()),
+        Value::from(variant.signed),
+        Value::from(variant.altered),
+    ]
+}
+
+fn variant_from_row(row: &rusqlite::Row, start: usize) -> rusqlite::Result<Variant> {
+    Ok(Variant {
+        finish: Finish::from_str(&row.get::<_, String>(start)?).unwrap_or_default(),
+        condition: Condition::from_str(&row.get::<_, String>(start + 1)?).unwrap_or_default(),
+        language: row.get(start + 2)?,
+        signed: row.get(start + 3)?,
+        altered: row.get(start + 4)?,
+    })
+}
+
+/// `scryfall_id, face` starting at column `start`
+fn key_from_row(row: &rusqlite::Row, start: usize) -> rusqlite::Result<PrintingKey> {
+    Ok(PrintingKey {
+        scryfall_id: row.get(start)?,
+        face: row.get(start + 1)?,
+    })
+}
+
+fn history_entry_from_row(row: &rusqlite::Row) -> rusqlite::Result<HistoryEntry> {
+    Ok(HistoryEntry {
+        key: key_from_row(row, 0)?,
+        change_type: ChangeType::from_str(&row.get::<_, String>(2)?),
+        previous_value: row.get(3)?,
+        updated_value: row.get(4)?,
+        variant: variant_from_row(row, 5)?,
+        created_at: row.get(10)?,
+        session_id: row.get(11)?,
+        client_id: row.get(12)?,
+        counterpart: row.get(13)?,
+        transfer_id: row.get(14)?,
+    })
+}
+
+/// Where a change came from: one scanning session (a browser tab, across reloads and reconnects, or one run of a
+/// command) on one client
+pub(crate) struct Session {
+    pub(crate) id: String,
+    pub(crate) client_id: String,
+    /// The collection changes go into unless an action names another one
+    pub(crate) collection: String,
+    /// Where newly added copies are put away, if anywhere. It's a container in `collection`
+    pub(crate) target: Option<Target>,
+    /// Whether confidently recognised cards are added when they leave the frame, without a tap
+    pub(crate) auto_accept: bool,
+    /// The last card added that way, so it can be undone for a little while
+    pub(crate) accepted: Option<Accepted>,
+    /// Set codes pinned to this session's scanning context, see `search::set_weights`
+    pub(crate) pinned_sets: Vec<String>,
+}
+
+impl Session {
+    pub(crate) fn new(client_id: &str) -> Self {
+        static SESSION_COUNT: AtomicUsize = AtomicUsize::new(0);
+        Self {
+            id: format!(
+                "{}-{}",
+                Utc::now().format("%Y%m%dT%H%M%SZ"),
+                SESSION_COUNT.fetch_add(1, atomic::Ordering::Relaxed)
+            ),
+            client_id: client_id.to_owned(),
+            collection: DEFAULT_COLLECTION.to_owned(),
+            target: None,
+            auto_accept: AUTO_ACCEPT.enabled,
+            accepted: None,
+            pinned_sets: vec![],
+        }
+    }
+
+    /// Move on to the next binder slot after filling one, boxes and decks just keep going into the same place
+    pub(crate) fn advance_target(&mut self) {
+        if let Some(target) = &mut self.target {
+            if target.container.kind == ContainerKind::Binder && target.position.slot > 0 {
+                target.position.slot += 1;
+                if target.position.slot > BINDER_SLOTS {
+                    target.position.slot = 1;
+                    target.position.section += 1;
+                }
+            }
+        }
+    }
+}
+
+/// A card that was auto accepted, with what it takes to put everything back if it's undone
+pub(crate) struct Accepted {
+    pub(crate) key: PrintingKey,
+    /// The collection it went into and the history entry that added it there
+    pub(crate) collection: String,
+    pub(crate) entry: i64,
+    /// The session's target before it moved on past this card
+    pub(crate) target: Option<Target>,
+    /// Oracle ids to leave out when the card is read again after an undo, the accepted one and any rejected before it
+    pub(crate) rejected: HashSet<String>,
+    pub(crate) at: Instant,
+}
+
+/// Slots on one binder page
+const BINDER_SLOTS: usize = 9;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum ContainerKind {
+    Binder,
+    Box,
+    Deck,
+}
+
+impl ContainerKind {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            ContainerKind::Binder => "binder",
+            ContainerKind::Box => "box",
+            ContainerKind::Deck => "deck",
+        }
+    }
+
+    pub(crate) fn from_str(kind: &str) -> Option<Self> {
+        match kind.to_lowercase().as_str() {
+            "binder" => Some(ContainerKind::Binder),
+            "box" => Some(ContainerKind::Box),
+            "deck" => Some(ContainerKind::Deck),
+            _ => None,
+        }
+    }
+}
+
+/// Somewhere physical that copies are kept
+#[derive(Clone, Debug)]
+pub(crate) struct Container {
+    pub(crate) id: i64,
+    pub(crate) name: String,
+    pub(crate) kind: ContainerKind,
+}
+
+/// Where in a container a copy is: binder page and slot, or box row. 0 means not given
+#[derive(Clone, Copy, PartialEq, Default, Debug)]
+pub(crate) struct Position {
+    /// Binder page or box row
+    pub(crate) section: usize,
+    /// Binder slot, unused for boxes and decks
+    pub(crate) slot: usize,
+}
+
+impl Position {
+    pub(crate) fn describe(&self, kind: ContainerKind) -> String {
+        match (kind, self.section, self.slot) {
+            (_, 0, _) | (ContainerKind::Deck, _, _) => String::new(),
+            (ContainerKind::Binder, page, 0) => format!("page {}", page),
+            (ContainerKind::Binder, page, slot) => format!("page {}, slot {}", page, slot),
+            (ContainerKind::Box, row, _) => format!("row {}", row),
+        }
+    }
+}
+
+/// Where a session's newly added copies go
+#[derive(Clone, Debug)]
+pub(crate) struct Target {
+    pub(crate) container: Container,
+    pub(crate) position: Position,
+}
+
+impl Target {
+    pub(crate) fn to_json(&self) -> String {
+        format!(
+            r#"{{"container": {}, "kind": "{}", "position": "{}"}}"#,
+            serde_json::to_string(&self.container.name).unwrap(),
+            self.container.kind.as_str(),
+            self.position.describe(self.container.kind)
+        )
+    }
+}
+
+/// Some copies of one variant of a printing, put away somewhere
+pub(crate) struct Location {
+    pub(crate) container: Container,
+    pub(crate) position: Position,
+    pub(crate) variant: Variant,
+    pub(crate) count: usize,
+}
+
+impl Location {
+    /// `Trade Binder page 3, slot 4`
+    pub(crate) fn describe(&self) -> String {
+        let position = self.position.describe(self.container.kind);
+        if position.is_empty() {
+            self.container.name.clone()
+        } else {
+            format!("{} {}", self.container.name, position)
+        }
+    }
+
+    fn to_json(&self) -> String {
+        format!(
+            r#"{{"container": {}, "kind": "{}", "position": "{}", {}, "count": "{}"}}"#,
+            serde_json::to_string(&self.container.name).unwrap(),
+            self.container.kind.as_str(),
+            self.position.describe(self.container.kind),
+            self.variant.to_json(),
+            self.count
+        )
+    }
+}
+
+/// Which changes the history view shows
+pub(crate) enum HistoryFilter {
+    All,
+    /// Since local midnight
+    Today,
+    Session(String),
+}
+
+impl HistoryFilter {
+    /// `since` and `session_id` parameters for `FILTERED_HISTORY`
+    fn params(&self) -> (Option<i64>, Option<&str>) {
+        match self {
+            HistoryFilter::All => (None, None),
+            HistoryFilter::Today => (
+                Local::now()
+                    .date_naive()
+                    .and_hms_opt(0, 0, 0)
+                    .and_then(|midnight| midnight.and_local_timezone(Local).earliest())
+                    .map(|midnight| midnight.timestamp()),
+                None,
+            ),
+            HistoryFilter::Session(session_id) => (None, Some(session_id)),
+        }
+    }
+}
+
+/// Active history entries, filtered by `HistoryFilter::params` as ?2 and ?3
+const FILTERED_HISTORY: &str = "FROM history
+    WHERE undone = ?1
+    AND (?2 IS NULL OR created_at >= ?2)
+    AND (?3 IS NULL OR session_id = ?3)";
+
+/// `history.undone` values
+const ACTIVE: usize = 0;
+/// Undone, and can still be redone
+const UNDONE: usize = 1;
+/// Undone, then replaced by a newer change so it can't be redone anymore
+const DISCARDED: usize = 2;
+
+/// How many copies of one variant of a printing we have
+pub(crate) struct Holding {
+    pub(crate) key: PrintingKey,
+    pub(crate) variant: Variant,
+    pub(crate) count: usize,
+}
+
+/// How big a collection is and what it's worth
+#[derive(Default)]
+pub(crate) struct Totals {
+    pub(crate) cards: usize,
+    /// In `PRICING`'s currency
+    pub(crate) value: f64,
+    /// Copies we couldn't find any price for, they aren't in `value`
+    pub(crate) unpriced: usize,
+}
+
+impl Totals {
+    pub(crate) fn add(&mut self, count: usize, price: Option<Price>) {
+        self.cards += count;
+        match price {
+            Some(price) => self.value += price.amount * count as f64,
+            None => self.unpriced += count,
+        }
+    }
+}
+
+/// A variant whose stored count doesn't match what replaying the history gives
+pub(crate) struct Drift {
+    pub(crate) key: PrintingKey,
+    pub(crate) variant: Variant,
+    pub(crate) snapshot: usize,
+    pub(crate) replayed: usize,
+}
+
+/// The layout of the old `database.json`, only kept around to migrate it
+#[derive(Deserialize, Default)]
+struct JsonDatabase {
+    database: HashMap<String, JsonCardCounts>,
+    history: Vec<JsonHistoryEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonCardCounts {
+    non_foil: usize,
+    foil: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonHistoryEntry {
+    file_name: String,
     change_type: ChangeType,
     updated_value: usize,
     foil: bool,
 }
 
-#[derive(Serialize, Deserialize, Default)]
-pub(crate) struct CardCounts {
-    non_foil: usize,
-    foil: usize,
+/// Schema changes, in order. `PRAGMA user_version` tracks how many have been applied
+const MIGRATIONS: &[&str] = &[r#"
+    CREATE TABLE holdings (
+        file_name TEXT PRIMARY KEY,
+        non_foil INTEGER NOT NULL DEFAULT 0,
+        foil INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE TABLE history (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        file_name TEXT NOT NULL,
+        change_type TEXT NOT NULL,
+        updated_value INTEGER NOT NULL,
+        foil INTEGER NOT NULL
+    );
+"#, r#"
+    ALTER TABLE history ADD COLUMN previous_value INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE history ADD COLUMN undone INTEGER NOT NULL DEFAULT 0;
+    UPDATE history SET previous_value = COALESCE((
+        SELECT earlier.updated_value FROM history AS earlier
+        WHERE earlier.file_name = history.file_name AND earlier.foil = history.foil AND earlier.id < history.id
+        ORDER BY earlier.id DESC LIMIT 1
+    ), 0);
+"#, r#"
+    ALTER TABLE history ADD COLUMN created_at INTEGER;
+"#, r#"
+    ALTER TABLE history ADD COLUMN session_id TEXT;
+    ALTER TABLE history ADD COLUMN client_id TEXT;
+"#, r#"
+    CREATE TABLE variants (
+        file_name TEXT NOT NULL,
+        finish TEXT NOT NULL,
+        condition TEXT NOT NULL,
+        language TEXT NOT NULL,
+        signed INTEGER NOT NULL,
+        altered INTEGER NOT NULL,
+        count INTEGER NOT NULL DEFAULT 0,
+        PRIMARY KEY (file_name, finish, condition, language, signed, altered)
+    );
+    INSERT INTO variants (file_name, finish, condition, language, signed, altered, count)
+        SELECT file_name, 'nonfoil', 'NM', 'en', 0, 0, non_foil FROM holdings WHERE non_foil > 0;
+    INSERT INTO variants (file_name, finish, condition, language, signed, altered, count)
+        SELECT file_name, 'foil', 'NM', 'en', 0, 0, foil FROM holdings WHERE foil > 0;
+    DROP TABLE holdings;
+    ALTER TABLE variants RENAME TO holdings;
+    ALTER TABLE history ADD COLUMN finish TEXT NOT NULL DEFAULT 'nonfoil';
+    ALTER TABLE history ADD COLUMN condition TEXT NOT NULL DEFAULT 'NM';
+    ALTER TABLE history ADD COLUMN language TEXT NOT NULL DEFAULT 'en';
+    ALTER TABLE history ADD COLUMN signed INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE history ADD COLUMN altered INTEGER NOT NULL DEFAULT 0;
+    UPDATE history SET finish = 'foil' WHERE foil = 1;
+"#, r#"
+    CREATE TABLE containers (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        name TEXT NOT NULL UNIQUE,
+        kind TEXT NOT NULL
+    );
+    CREATE TABLE locations (
+        file_name TEXT NOT NULL,
+        finish TEXT NOT NULL,
+        condition TEXT NOT NULL,
+        language TEXT NOT NULL,
+        signed INTEGER NOT NULL,
+        altered INTEGER NOT NULL,
+        container_id INTEGER NOT NULL REFERENCES containers (id),
+        section INTEGER NOT NULL DEFAULT 0,
+        slot INTEGER NOT NULL DEFAULT 0,
+        count INTEGER NOT NULL DEFAULT 0,
+        PRIMARY KEY (file_name, finish, condition, language, signed, altered, container_id, section, slot)
+    );
+"#, r#"
+    ALTER TABLE history ADD COLUMN counterpart TEXT;
+"#, r#"
+    CREATE TEMP TABLE printing_keys AS
+        SELECT file_name, rtrim(file_name, replace(file_name, '-', '')) AS prefix
+        FROM (SELECT file_name FROM holdings UNION SELECT file_name FROM history UNION SELECT file_name FROM locations);
+    ALTER TABLE printing_keys ADD COLUMN scryfall_id TEXT;
+    ALTER TABLE printing_keys ADD COLUMN face INTEGER;
+    UPDATE printing_keys SET
+        scryfall_id = CASE WHEN prefix = '' THEN file_name ELSE substr(prefix, 1, length(prefix) - 1) END,
+        face = CASE WHEN prefix = '' THEN 0 ELSE CAST(substr(file_name, length(prefix) + 1) AS INTEGER) END;
+
+    CREATE TABLE keyed_holdings (
+        scryfall_id TEXT NOT NULL,
+        face INTEGER NOT NULL,
+        finish TEXT NOT NULL,
+        condition TEXT NOT NULL,
+        language TEXT NOT NULL,
+        signed INTEGER NOT NULL,
+        altered INTEGER NOT NULL,
+        count INTEGER NOT NULL DEFAULT 0,
+        PRIMARY KEY (scryfall_id, face, finish, condition, language, signed, altered)
+    );
+    INSERT INTO keyed_holdings (scryfall_id, face, finish, condition, language, signed, altered, count)
+        SELECT scryfall_id, face, finish, condition, language, signed, altered, SUM(count)
+        FROM holdings JOIN printing_keys USING (file_name)
+        GROUP BY scryfall_id, face, finish, condition, language, signed, altered;
+    DROP TABLE holdings;
+    ALTER TABLE keyed_holdings RENAME TO holdings;
+
+    CREATE TABLE keyed_locations (
+        scryfall_id TEXT NOT NULL,
+        face INTEGER NOT NULL,
+        finish TEXT NOT NULL,
+        condition TEXT NOT NULL,
+        language TEXT NOT NULL,
+        signed INTEGER NOT NULL,
+        altered INTEGER NOT NULL,
+        container_id INTEGER NOT NULL REFERENCES containers (id),
+        section INTEGER NOT NULL DEFAULT 0,
+        slot INTEGER NOT NULL DEFAULT 0,
+        count INTEGER NOT NULL DEFAULT 0,
+        PRIMARY KEY (scryfall_id, face, finish, condition, language, signed, altered, container_id, section, slot)
+    );
+    INSERT INTO keyed_locations (scryfall_id, face, finish, condition, language, signed, altered, container_id, section, slot, count)
+        SELECT scryfall_id, face, finish, condition, language, signed, altered, container_id, section, slot, SUM(count)
+        FROM locations JOIN printing_keys USING (file_name)
+        GROUP BY scryfall_id, face, finish, condition, language, signed, altered, container_id, section, slot;
+    DROP TABLE locations;
+    ALTER TABLE keyed_locations RENAME TO locations;
+
+    ALTER TABLE history ADD COLUMN scryfall_id TEXT NOT NULL DEFAULT '';
+    ALTER TABLE history ADD COLUMN face INTEGER NOT NULL DEFAULT 0;
+    UPDATE history SET
+        scryfall_id = (SELECT scryfall_id FROM printing_keys WHERE printing_keys.file_name = history.file_name),
+        face = (SELECT face FROM printing_keys WHERE printing_keys.file_name = history.file_name);
+    ALTER TABLE history DROP COLUMN file_name;
+    DROP TABLE printing_keys;
+"#, r#"
+    ALTER TABLE history ADD COLUMN undone_at INTEGER;
+"#, r#"
+    ALTER TABLE history ADD COLUMN transfer_id TEXT;
+"#];
+
+/// The migration that replaced image file names with `PrintingKey`s, databases from before it get checked for
+/// printings that no longer resolve
+const PRINTING_KEY_MIGRATION: usize = 8;
+
+/// A collection's database, opened (and checked, migrated and backed up) the first time it's asked for
+pub(crate) fn collection(name: &str) -> Result<Arc<Mutex<CardDatabase>>> {
+    let mut collections = COLLECTIONS.lock().unwrap();
+    if let Some(database) = collections.get(name) {
+        return Ok(database.clone());
+    }
+    if !is_valid_name(name) {
+        bail!(
+            "Collection names can only use letters, numbers, - and _, not {}",
+            name
+        );
+    }
+
+    let database = Arc::new(Mutex::new(CardDatabase::open(name)?));
+    collections.insert(name.to_owned(), database.clone());
+    Ok(database)
+}
+
+/// A collection's database if it already exists, for anything that only reads it, so a typo in a name doesn't leave
+/// an empty collection behind. The default collection always exists, since it's created on first run
+pub(crate) fn existing_collection(name: &str) -> Result<Option<Arc<Mutex<CardDatabase>>>> {
+    let exists = name == DEFAULT_COLLECTION
+        || COLLECTIONS.lock().unwrap().contains_key(name)
+        || (is_valid_name(name) && fs::metadata(collection_path(name)).is_ok());
+    if !exists {
+        return Ok(None);
+    }
+    collection(name).map(Some)
+}
+
+/// An existing collection's database, or an error saying there's no such collection
+fn named_collection(name: &str) -> Result<Arc<Mutex<CardDatabase>>> {
+    match existing_collection(name)? {
+        Some(database) => Ok(database),
+        None => bail!("There's no collection named {}", name),
+    }
+}
+
+/// Back up every collection, the server does this when it starts
+pub(crate) fn backup_collections() {
+    for name in collection_names() {
+        if let Err(e) = collection(&name).and_then(|database| database.lock().unwrap().backup()) {
+            eprintln!("Failed to back up collection {}: {}", name, e);
+        }
+    }
+}
+
+fn collection_path(name: &str) -> String {
+    if name == DEFAULT_COLLECTION {
+        DATABASE_PATH.to_owned()
+    } else {
+        format!("{}/{}.sqlite", COLLECTION_DIRECTORY, name)
+    }
+}
+
+/// Every collection there's a database for, the default first
+pub(crate) fn collection_names() -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(COLLECTION_DIRECTORY)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| {
+                    let path = entry.ok()?.path();
+                    let name = path.file_name()?.to_str()?.strip_suffix(".sqlite")?;
+                    is_valid_name(name).then(|| name.to_owned())
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    names.sort();
+    names.retain(|name| name != DEFAULT_COLLECTION);
+    names.insert(0, DEFAULT_COLLECTION.to_owned());
+    names
+}
+
+/// Names end up in file paths, so keep them simple
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Move copies from one collection to another, recording it in both histories
+/// Returns how many were moved, which is fewer than `count` if the source doesn't have that many
+pub(crate) fn transfer(
+    session: &Session,
+    from: &str,
+    to: &str,
+    key: &PrintingKey,
+    variant: &Variant,
+    count: usize,
+) -> Result<usize> {
+    if from == to {
+        bail!("Can't transfer from {} to itself", from);
+    }
+    let source = named_collection(from)?;
+    let destination = named_collection(to)?;
+    let transfer_id = format!(
+        "{}-{}",
+        session.id,
+        SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos()
+    );
+
+    // Only one collection is locked at a time, so two transfers going opposite ways can't deadlock
+    let mut moved = 0;
+    let Some(id) = source.lock().unwrap().change(
+        session,
+        &Change {
+            counterpart: Some(to),
+            transfer_id: Some(&transfer_id),
+            ..Change::new(key, ChangeType::Transfer, variant)
+        },
+        |current| {
+            moved = count.min(current.unwrap_or_default());
+            (moved > 0).then(|| current.unwrap_or_default() - moved)
+        },
+    )?
+    else {
+        return Ok(0);
+    };
+    let credited = destination.lock().unwrap().change(
+        session,
+        &Change {
+            counterpart: Some(from),
+            transfer_id: Some(&transfer_id),
+            ..Change::new(key, ChangeType::Transfer, variant)
+        },
+        |current| Some(current.unwrap_or_default() + moved),
+    );
+    if let Err(e) = credited {
+        // Give the copies back rather than losing them from both collections
+        match source.lock().unwrap().revert(id, DISCARDED) {
+            Ok(Some(_)) => (),
+            Ok(None) => eprintln!("{} changed in {} before the failed transfer could be given back", key, from),
+            Err(revert_error) => eprintln!("Failed to give {} back to {}: {}", key, from, revert_error),
+        }
+        return Err(e.into());
+    }
+    Ok(moved)
+}
+
+/// Undo the newest change in a collection. Both sides of a transfer are undone together, so the copies go back where
+/// they came from instead of ending up in both collections
+pub(crate) fn undo(name: &str) -> Result<Option<HistoryEntry>> {
+    let database = named_collection(name)?;
+    let Some((id, entry)) = database.lock().unwrap().undo()? else {
+        return Ok(None);
+    };
+    if let Err(e) = other_side(&entry, ACTIVE, |other, id| other.revert(id, UNDONE)) {
+        // Leave both sides as they were rather than half undone
+        database.lock().unwrap().reapply(id)?;
+        bail!(
+            "the other side of the transfer couldn't be undone ({}), undo the newer changes in {} first",
+            e,
+            entry.counterpart.as_deref().unwrap_or_default()
+        );
+    }
+    Ok(Some(entry))
+}
+
+/// Redo the oldest undone change in a collection, along with the other side of a transfer
+pub(crate) fn redo(name: &str) -> Result<Option<HistoryEntry>> {
+    let database = named_collection(name)?;
+    let Some((id, entry)) = database.lock().unwrap().redo()? else {
+        return Ok(None);
+    };
+    if let Err(e) = other_side(&entry, UNDONE, |other, id| other.reapply(id)) {
+        // The other side can't come back, most likely because that collection has changed since, so this side
+        // can't either. Take it off the redo stack so it doesn't block everything after it
+        database.lock().unwrap().revert(id, DISCARDED)?;
+        bail!("the other side of the transfer couldn't be redone ({})", e);
+    }
+    Ok(Some(entry))
+}
+
+/// Apply `apply` to the other collection's entry for a transfer, which should be in state `undone`
+/// Anything that isn't a transfer (or is from before transfers were linked) has no other side
+fn other_side(
+    entry: &HistoryEntry,
+    undone: usize,
+    apply: impl FnOnce(&mut CardDatabase, i64) -> rusqlite::Result<Option<HistoryEntry>>,
+) -> Result<()> {
+    let (Some(counterpart), Some(transfer_id)) = (&entry.counterpart, &entry.transfer_id) else {
+        return Ok(());
+    };
+    let other = named_collection(counterpart)?;
+    let mut other = other.lock().unwrap();
+    let id: Option<i64> = other
+        .connection
+        .query_row(
+            "SELECT id FROM history WHERE transfer_id = ?1 AND undone = ?2",
+            params![transfer_id, undone],
+            |row| row.get(0),
+        )
+        .optional()?;
+    match id {
+        Some(id) if apply(&mut other, id)?.is_some() => Ok(()),
+        Some(_) => bail!("{} has changed since", entry.key),
+        None => bail!("{} has no matching entry", counterpart),
+    }
 }
 
-#[derive(Serialize, Deserialize, Default)]
 pub(crate) struct CardDatabase {
-    database: HashMap<String, CardCounts>,
-    pub(crate) history: Vec<HistoryEntry>,
+    name: String,
+    connection: Connection,
 }
 
 impl CardDatabase {
-    fn load() -> io::Result<Self> {
-        let file = fs::File::open("./database.json").map_err(|err| {
-            if err.kind() == io::ErrorKind::NotFound {
-                File::create("./database.json").unwrap();
-                io::Error::new(io::ErrorKind::NotFound, "File created")
-            } else {
-                err
+    fn open(name: &str) -> Result<Self> {
+        if name != DEFAULT_COLLECTION {
+            fs::create_dir_all(COLLECTION_DIRECTORY)?;
+        }
+        let path = collection_path(name);
+        if !is_valid(&path) {
+            restore_backup(&path, &backup_prefix(name))?;
+        }
+
+        let mut connection = Connection::open(&path)?;
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        let version = migrate(&mut connection, MIGRATIONS)?;
+
+        let mut database = Self {
+            name: name.to_owned(),
+            connection,
+        };
+        if name == DEFAULT_COLLECTION {
+            database.import_json("./database.json")?;
+        }
+        if version < PRINTING_KEY_MIGRATION {
+            database.report_unresolved();
+        }
+        Ok(database)
+    }
+
+    /// One-time import of the old whole-file JSON database, it's renamed afterwards so it's never imported twice
+    /// The old save could crash between moving the live file to `.bak` and finishing the new one, so fall back to
+    /// the `.bak` if needed, and refuse to continue rather than import an empty collection
+    fn import_json(&mut self, path: &str) -> Result<()> {
+        let backup_path = format!("{}.bak", path);
+        let candidates: Vec<&str> = [path, backup_path.as_str()]
+            .into_iter()
+            .filter(|candidate| fs::metadata(candidate).is_ok())
+            .collect();
+        if candidates.is_empty() {
+            return Ok(());
+        }
+
+        println!("Migrating {} into the collection database...", path);
+        let Some(json) = candidates.iter().find_map(|candidate| match read_json(candidate) {
+            Ok(json) => Some(json),
+            Err(e) => {
+                eprintln!("{} is corrupt: {}", candidate, e);
+                None
             }
-        })?;
+        }) else {
+            bail!(
+                "{} couldn't be read and there's no valid backup of it, fix or remove it to continue",
+                path
+            );
+        };
 
-        serde_json::from_reader(file).or(Ok(Self {
-            database: HashMap::new(),
-            history: Vec::new(),
-        }))
+        let transaction = self.connection.transaction()?;
+        let mut previous_values: HashMap<(&str, bool), usize> = HashMap::new();
+        for (file_name, counts) in json.database.iter() {
+            let key = json_key(file_name);
+            set_count(&transaction, &key, &Variant::new(Finish::NonFoil), counts.non_foil)?;
+            set_count(&transaction, &key, &Variant::new(Finish::Foil), counts.foil)?;
+        }
+        for entry in json.history.iter() {
+            let previous_value = previous_values
+                .insert((&entry.file_name, entry.foil), entry.updated_value)
+                .unwrap_or_default();
+            let finish = if entry.foil { Finish::Foil } else { Finish::NonFoil };
+            let key = json_key(&entry.file_name);
+            transaction.execute(
+                "INSERT INTO history (scryfall_id, face, change_type, previous_value, updated_value, foil, finish) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    key.scryfall_id,
+                    key.face,
+                    entry.change_type.as_str(),
+                    previous_value,
+                    entry.updated_value,
+                    entry.foil,
+                    finish.as_str()
+                ],
+            )?;
+        }
+        transaction.commit()?;
+
+        for candidate in candidates {
+            fs::rename(candidate, format!("{}.migrated", candidate))?;
+        }
+        println!(
+            "Migrated {} cards and {} history entries.",
+            json.database.len(),
+            json.history.len()
+        );
+        Ok(())
     }
 
-    fn save(&self) -> io::Result<()> {
-        if fs::metadata("./database.json").is_ok() {
-            fs::rename("./database.json", "./database.json.bak")?;
+    /// Printings we have copies of that aren't in the card data, or that we don't have an image for
+    pub(crate) fn unresolved(&self) -> Vec<Holding> {
+        let cards = card_store::cards();
+        self.holdings()
+            .into_iter()
+            .filter(|holding| {
+                holding.count > 0
+                    && (cards.get_card_by_id(&holding.key.scryfall_id).is_none()
+                        || cards.image(&holding.key).is_none())
+            })
+            .collect()
+    }
+
+    fn report_unresolved(&self) {
+        let unresolved = self.unresolved();
+        if unresolved.is_empty() {
+            return;
         }
+        eprintln!(
+            "{} printings in the {} collection don't match any card or image, their copies are kept but won't show up:",
+            unresolved.len(),
+            self.name
+        );
+        for holding in unresolved {
+            eprintln!("  {} ({} copies)", holding.key, holding.count);
+        }
+    }
 
-        let mut file = File::create("./database.json")?;
-        file.write_all(serde_json::to_string_pretty(&self)?.as_bytes())?;
-        file.sync_all()?;
-        fs::remove_file("./database.json.bak")
+    /// Write a consistent copy of the database to a temp file, fsync it, then move it into place
+    /// Only the newest `BACKUPS_KEPT` backups are kept
+    fn backup(&self) -> Result<()> {
+        fs::create_dir_all(BACKUP_DIRECTORY)?;
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let prefix = backup_prefix(&self.name);
+        let backup_path = format!("{}/{}{}.sqlite", BACKUP_DIRECTORY, prefix, timestamp);
+        let temp_path = format!("{}.tmp", backup_path);
+
+        let _ = fs::remove_file(&temp_path);
+        self.connection
+            .execute("VACUUM INTO ?1", params![temp_path])?;
+        File::open(&temp_path)?.sync_all()?;
+        fs::rename(&temp_path, &backup_path)?;
+
+        let backups = backups(&prefix)?;
+        for old_backup in backups.iter().take(backups.len().saturating_sub(BACKUPS_KEPT)) {
+            fs::remove_file(old_backup)?;
+        }
+        Ok(())
     }
 
-    fn record_change(&mut self, file_name: String, change_type: ChangeType, foil: bool) {
-        let card_counts = &self.database[&file_name];
-        let updated_value = if foil {
-            card_counts.foil
-        } else {
-            card_counts.non_foil
-        };
-        let entry = HistoryEntry {
-            file_name,
-            change_type,
-            updated_value,
-            foil,
-        };
-        self.history.push(entry);
+    /// Apply a change to one count and record it in the history, all in one transaction
+    /// `update` gets the current count (`None` if we've never had the card) and returns the new one, or `None` to do nothing
+    /// Returns the new history entry's id, if there is one
+    fn change(
+        &mut self,
+        session: &Session,
+        change: &Change,
+        update: impl FnOnce(Option<usize>) -> Option<usize>,
+    ) -> rusqlite::Result<Option<i64>> {
+        let transaction = self.connection.transaction()?;
+        let id = apply_change(&transaction, &self.name, session, change, update)?;
+        transaction.commit()?;
+        Ok(id)
     }
 
-    pub(crate) fn inc(&mut self, id: &str, foil: bool) {
-        let counts = self
-            .database
-            .entry(id.to_string())
-            .or_default();
-        if foil {
-            counts.foil += 1;
-        } else {
-            counts.non_foil += 1;
-        };
+    /// Add copies to the collection in one transaction, so an import either happens completely or not at all
+    /// Imports can be big, so it's backed up first
+    pub(crate) fn import(&mut self, session: &Session, copies: &[(PrintingKey, Variant, usize)]) -> Result<()> {
+        self.backup()?;
+        let transaction = self.connection.transaction()?;
+        for (key, variant, count) in copies {
+            apply_change(
+                &transaction,
+                &self.name,
+                session,
+                &Change::new(key, ChangeType::Import, variant),
+                |current| Some(current.unwrap_or_default() + count),
+            )?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
 
-        self.record_change(id.to_string(), ChangeType::Inc, foil);
-        if let Err(e) = self.save() {
-            eprintln!("Failed to save data: {}", e);
+    /// Revert the newest change that's still in effect, returning it and its id if there was one
+    /// Use `card_database::undo`, which also takes care of the other side of transfers
+    fn undo(&mut self) -> rusqlite::Result<Option<(i64, HistoryEntry)>> {
+        let id: Option<i64> = self
+            .connection
+            .query_row(
+                "SELECT id FROM history WHERE undone = ?1 ORDER BY id DESC LIMIT 1",
+                params![ACTIVE],
+                |row| row.get(0),
+            )
+            .optional()?;
+        match id {
+            Some(id) => Ok(self.revert(id, UNDONE)?.map(|entry| (id, entry))),
+            None => Ok(None),
         }
     }
 
-    pub(crate) fn dec(&mut self, id: &str, foil: bool) {
-        if let Some(counts) = self.database.get_mut(id) {
-            if foil && counts.foil > 0 {
-                counts.foil -= 1;
-            } else if !foil && counts.non_foil > 0 {
-                counts.non_foil -= 1;
-            }
-            self.record_change(id.to_string(), ChangeType::Dec, foil);
-            if let Err(e) = self.save() {
+    /// Re-apply the oldest undone change, returning it and its id if there was one
+    fn redo(&mut self) -> rusqlite::Result<Option<(i64, HistoryEntry)>> {
+        let id: Option<i64> = self
+            .connection
+            .query_row(
+                "SELECT id FROM history WHERE undone = ?1 ORDER BY id ASC LIMIT 1",
+                params![UNDONE],
+                |row| row.get(0),
+            )
+            .optional()?;
+        match id {
+            Some(id) => Ok(self.reapply(id)?.map(|entry| (id, entry))),
+            None => Ok(None),
+        }
+    }
+
+    /// Take back one particular change for good, rather than the newest: it can't be redone, and the rest of the undo
+    /// and redo stacks are left alone. Nothing happens (and it returns `None`) if that variant has changed since
+    pub(crate) fn take_back(&mut self, id: i64) -> rusqlite::Result<Option<HistoryEntry>> {
+        self.revert(id, DISCARDED)
+    }
+
+    /// Revert one change, if it's still in effect and nothing has changed that variant since, returning it if so
+    /// It's marked `undone` afterwards: `UNDONE` so it can be redone, or `DISCARDED` if it shouldn't be
+    fn revert(&mut self, id: i64, undone: usize) -> rusqlite::Result<Option<HistoryEntry>> {
+        let transaction = self.connection.transaction()?;
+        let Some(entry) = latest_entry(&transaction, id, ACTIVE)? else {
+            return Ok(None);
+        };
+        set_count(&transaction, &entry.key, &entry.variant, entry.previous_value)?;
+        trim_locations(&transaction, &entry.key, &entry.variant, entry.previous_value, None)?;
+        transaction.execute(
+            "UPDATE history SET undone = ?1, undone_at = ?2 WHERE id = ?3",
+            params![undone, Utc::now().timestamp(), id],
+        )?;
+        transaction.commit()?;
+        Ok(Some(entry))
+    }
+
+    /// Re-apply one undone change, if nothing has changed that variant since, returning it if so
+    fn reapply(&mut self, id: i64) -> rusqlite::Result<Option<HistoryEntry>> {
+        let transaction = self.connection.transaction()?;
+        let Some(entry) = latest_entry(&transaction, id, UNDONE)? else {
+            return Ok(None);
+        };
+        set_count(&transaction, &entry.key, &entry.variant, entry.updated_value)?;
+        trim_locations(&transaction, &entry.key, &entry.variant, entry.updated_value, None)?;
+        transaction.execute(
+            "UPDATE history SET undone = ?1, undone_at = NULL WHERE id = ?2",
+            params![ACTIVE, id],
+        )?;
+        transaction.commit()?;
+        Ok(Some(entry))
+    }
+
+    /// Returns the history entry it made, if it could be saved
+    pub(crate) fn inc(&mut self, session: &Session, key: &PrintingKey, variant: &Variant) -> Option<i64> {
+        match self.change(session, &Change::new(key, ChangeType::Inc, variant), |count| {
+            Some(count.unwrap_or_default() + 1)
+        }) {
+            Ok(entry) => entry,
+            Err(e) => {
                 eprintln!("Failed to save data: {}", e);
+                None
             }
         }
     }
 
-    pub(crate) fn set(&mut self, id: &str, value: usize, foil: bool) {
-        if foil {
-            self.database
-                .entry(id.to_string())
-                .or_default()
-                .foil = value;
-        } else {
-            self.database
-                .entry(id.to_string())
-                .or_default()
-                .non_foil = value;
+    pub(crate) fn dec(&mut self, session: &Session, key: &PrintingKey, variant: &Variant) {
+        if let Err(e) = self.change(session, &Change::new(key, ChangeType::Dec, variant), |count| {
+            count.map(|count| count.saturating_sub(1))
+        }) {
+            eprintln!("Failed to save data: {}", e);
         }
-        self.record_change(id.to_string(), ChangeType::Set, foil);
-        if let Err(e) = self.save() {
+    }
+
+    pub(crate) fn set(&mut self, session: &Session, key: &PrintingKey, value: usize, variant: &Variant) {
+        if let Err(e) = self.change(session, &Change::new(key, ChangeType::Set, variant), |_| Some(value)) {
             eprintln!("Failed to save data: {}", e);
         }
     }
 
-    pub(crate) fn history(&self) -> (usize, f64, String) {
-        let total_cards = self.database.values().map(|c| c.non_foil + c.foil).sum();
-        let total_value = {
-            let scryrs = crate::search::CARDS.lock().unwrap();
+    /// Derive the counts purely from the history, optionally only using changes made up to `until`
+    /// Entries from before timestamps were recorded count as having happened before any date. Changes undone after
+    /// `until` were still in effect then, so they count (a change that's been redone counts from when it was made)
+    pub(crate) fn replay(
+        &self,
+        until: Option<DateTime<Utc>>,
+    ) -> rusqlite::Result<HashMap<(PrintingKey, Variant), usize>> {
+        let mut statement = self.connection.prepare_cached(&format!(
+            "SELECT scryfall_id, face, updated_value, {} FROM history
+             WHERE (undone = ?1 OR undone_at > ?2)
+             AND (?2 IS NULL OR created_at IS NULL OR created_at <= ?2)
+             ORDER BY id",
+            VARIANT_COLUMNS
+        ))?;
+        let mut holdings = HashMap::new();
+        let mut rows = statement.query(params![ACTIVE, until.map(|until| until.timestamp())])?;
+        while let Some(row) = rows.next()? {
+            holdings.insert((key_from_row(row, 0)?, variant_from_row(row, 3)?), row.get(2)?);
+        }
+        Ok(holdings)
+    }
+
+    /// Compare the stored counts against the replayed history, returning every variant where they disagree
+    pub(crate) fn verify(&self) -> rusqlite::Result<Vec<Drift>> {
+        let mut replayed = self.replay(None)?;
+        let mut drift = vec![];
+        for holding in self.holdings() {
+            let replayed = replayed
+                .remove(&(holding.key.clone(), holding.variant.clone()))
+                .unwrap_or_default();
+            if holding.count != replayed {
+                drift.push(Drift {
+                    key: holding.key,
+                    variant: holding.variant,
+                    snapshot: holding.count,
+                    replayed,
+                });
+            }
+        }
+        // Anything left was in the history but never made it into the holdings
+        drift.extend(
+            replayed
+                .into_iter()
+                .filter(|(_, replayed)| *replayed > 0)
+                .map(|((key, variant), replayed)| Drift {
+                    key,
+                    variant,
+                    snapshot: 0,
+                    replayed,
+                }),
+        );
+        Ok(drift)
+    }
+
+    /// Replace the stored counts with the replayed history, backing up first
+    pub(crate) fn rebuild(&mut self) -> Result<usize> {
+        self.backup()?;
+        let replayed = self.replay(None)?;
+        let transaction = self.connection.transaction()?;
+        transaction.execute("DELETE FROM holdings", [])?;
+        for ((key, variant), count) in replayed.iter() {
+            set_count(&transaction, key, variant, *count)?;
+            trim_locations(&transaction, key, variant, *count, None)?;
+        }
+        // Variants the history no longer has any copies of have no holding to trim against
+        transaction.execute(
+            "DELETE FROM locations WHERE NOT EXISTS (
+                 SELECT 1 FROM holdings
+                 WHERE holdings.scryfall_id = locations.scryfall_id AND holdings.face = locations.face
+                     AND holdings.finish = locations.finish AND holdings.condition = locations.condition
+                     AND holdings.language = locations.language AND holdings.signed = locations.signed
+                     AND holdings.altered = locations.altered AND holdings.count > 0
+             )",
+            [],
+        )?;
+        transaction.commit()?;
+        Ok(replayed.len())
+    }
+
+    /// Every variant we have (or have had) a count for
+    pub(crate) fn holdings(&self) -> Vec<Holding> {
+        let mut statement = self
+            .connection
+            .prepare_cached(&format!(
+                "SELECT scryfall_id, face, count, {} FROM holdings",
+                VARIANT_COLUMNS
+            ))
+            .unwrap();
+        statement
+            .query_map([], |row| {
+                Ok(Holding {
+                    key: key_from_row(row, 0)?,
+                    variant: variant_from_row(row, 3)?,
+                    count: row.get(2)?,
+                })
+            })
+            .unwrap()
+            .filter_map(|row| row.ok())
+            .collect()
+    }
+
+    /// The variants of one printing we have at least one copy of
+    pub(crate) fn variants(&self, key: &PrintingKey) -> Vec<(Variant, usize)> {
+        let mut statement = self
+            .connection
+            .prepare_cached(&format!(
+                "SELECT count, {} FROM holdings WHERE scryfall_id = ?1 AND face = ?2 AND count > 0
+                 ORDER BY finish, condition, language",
+                VARIANT_COLUMNS
+            ))
+            .unwrap();
+        statement
+            .query_map(params![key.scryfall_id, key.face], |row| {
+                Ok((variant_from_row(row, 1)?, row.get(0)?))
+            })
+            .unwrap()
+            .filter_map(|row| row.ok())
+            .collect()
+    }
+
+    /// The counts of a printing for the UI: totals per finish, every variant with its matching price, and where they're kept
+    pub(crate) fn counts_json(&self, key: &PrintingKey, card: &Card) -> String {
+        let variants = self
+            .variants(key)
+            .iter()
+            .map(|(variant, count)| {
+                format!(
+                    r#"{{{}, "count": "{}", {}}}"#,
+                    variant.to_json(),
+                    count,
+                    PRICING.json(PRICING.card_price(card, variant.finish))
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        let locations = self
+            .locations(key)
+            .iter()
+            .map(Location::to_json)
+            .collect::<Vec<_>>()
+            .join(", ");
+        format!(
+            r#""non_foil_count": "{}", "foil_count": "{}", "etched_count": "{}", "variants": [{}], "locations": [{}]"#,
+            self.get(key),
+            self.get_foil(key),
+            self.count(key, Finish::Etched),
+            variants,
+            locations
+        )
+    }
+
+    /// Make a new container, or get the existing one with that name
+    pub(crate) fn create_container(
+        &self,
+        name: &str,
+        kind: ContainerKind,
+    ) -> rusqlite::Result<Container> {
+        self.connection.execute(
+            "INSERT INTO containers (name, kind) VALUES (?1, ?2) ON CONFLICT (name) DO NOTHING",
+            params![name, kind.as_str()],
+        )?;
+        self.container(name)?.ok_or(rusqlite::Error::QueryReturnedNoRows)
+    }
+
+    pub(crate) fn container(&self, name: &str) -> rusqlite::Result<Option<Container>> {
+        self.connection
+            .query_row(
+                "SELECT id, name, kind FROM containers WHERE name = ?1",
+                params![name],
+                container_from_row,
+            )
+            .optional()
+    }
 
-            self.database.iter().fold(0.0, |prev, (uuid, counts)| {
-                let card = scryrs
-                    .get_card_by_id(&uuid[..uuid.rfind('-').unwrap()])
-                    .unwrap();
-                prev + card.usd() * (counts.non_foil as f64)
-                    + card.usd_foil() * (counts.foil as f64)
+    /// Every container and how many copies are in it
+    pub(crate) fn containers(&self) -> Vec<(Container, usize)> {
+        let mut statement = self
+            .connection
+            .prepare_cached(
+                "SELECT containers.id, containers.name, containers.kind, COALESCE(SUM(locations.count), 0)
+                 FROM containers LEFT JOIN locations ON locations.container_id = containers.id
+                 GROUP BY containers.id ORDER BY containers.name",
+            )
+            .unwrap();
+        statement
+            .query_map([], |row| Ok((container_from_row(row)?, row.get(3)?)))
+            .unwrap()
+            .filter_map(|row| row.ok())
+            .collect()
+    }
+
+    /// Where the copies of a printing are put away, copies that aren't anywhere aren't included
+    pub(crate) fn locations(&self, key: &PrintingKey) -> Vec<Location> {
+        let mut statement = self
+            .connection
+            .prepare_cached(&format!(
+                "SELECT containers.id, containers.name, containers.kind, section, slot, count, {}
+                 FROM locations JOIN containers ON locations.container_id = containers.id
+                 WHERE scryfall_id = ?1 AND face = ?2 AND count > 0
+                 ORDER BY containers.name, section, slot",
+                VARIANT_COLUMNS
+            ))
+            .unwrap();
+        statement
+            .query_map(params![key.scryfall_id, key.face], |row| {
+                Ok(Location {
+                    container: container_from_row(row)?,
+                    position: Position {
+                        section: row.get(3)?,
+                        slot: row.get(4)?,
+                    },
+                    count: row.get(5)?,
+                    variant: variant_from_row(row, 6)?,
+                })
             })
+            .unwrap()
+            .filter_map(|row| row.ok())
+            .collect()
+    }
+
+    /// Move up to `count` copies of a variant from one container to another, returning how many were moved
+    /// `None` as the source means copies that aren't in any container, and as the destination takes them out
+    pub(crate) fn move_copies(
+        &mut self,
+        key: &PrintingKey,
+        variant: &Variant,
+        from: Option<&Container>,
+        to: Option<&Target>,
+        count: usize,
+    ) -> rusqlite::Result<usize> {
+        let transaction = self.connection.transaction()?;
+        let available = match from {
+            Some(from) => located_count(&transaction, key, variant, Some(from.id))?,
+            None => {
+                let held: usize = transaction
+                    .query_row(
+                        &format!("SELECT COALESCE(SUM(count), 0) FROM holdings WHERE {}", VARIANT_MATCH),
+                        params_from_iter(variant_params(key, variant)),
+                        |row| row.get(0),
+                    )?;
+                held.saturating_sub(located_count(&transaction, key, variant, None)?)
+            }
+        };
+        let moving = count.min(available);
+        if moving == 0 {
+            return Ok(0);
+        }
+
+        if let Some(from) = from {
+            take_from_locations(&transaction, key, variant, Some(from.id), moving)?;
+        }
+        if let Some(to) = to {
+            add_to_location(&transaction, key, variant, to, moving)?;
+        }
+        transaction.commit()?;
+        Ok(moving)
+    }
+
+    /// The most recent changes that are still in effect, newest first
+    pub(crate) fn recent_history(&self, limit: usize) -> Vec<HistoryEntry> {
+        self.filtered_history(&HistoryFilter::All, Some(limit))
+    }
+
+    pub(crate) fn filtered_history(&self, filter: &HistoryFilter, limit: Option<usize>) -> Vec<HistoryEntry> {
+        let (since, session_id) = filter.params();
+        let mut statement = self
+            .connection
+            .prepare_cached(&format!(
+                "SELECT {} {} ORDER BY id DESC LIMIT ?4",
+                HISTORY_COLUMNS, FILTERED_HISTORY
+            ))
+            .unwrap();
+        statement
+            .query_map(
+                params![ACTIVE, since, session_id, limit.map_or(-1, |limit| limit as i64)],
+                history_entry_from_row,
+            )
+            .unwrap()
+            .filter_map(|row| row.ok())
+            .collect()
+    }
+
+    /// How many cards were added and removed by the filtered changes, and how fast they were added
+    fn history_summary(&self, filter: &HistoryFilter) -> String {
+        let entries = self.filtered_history(filter, None);
+        let added: usize = entries
+            .iter()
+            .map(|entry| entry.updated_value.saturating_sub(entry.previous_value))
+            .sum();
+        let removed: usize = entries
+            .iter()
+            .map(|entry| entry.previous_value.saturating_sub(entry.updated_value))
+            .sum();
+
+        let timestamps = entries.iter().filter_map(|entry| entry.created_at);
+        let hours = match (timestamps.clone().min(), timestamps.max()) {
+            (Some(first), Some(last)) if last > first => (last - first) as f64 / 3600.0,
+            _ => 0.0,
         };
+        let rate = if hours > 0.0 {
+            added as f64 / hours
+        } else {
+            0.0
+        };
+
+        format!(
+            r#"{{"added": "{}", "removed": "{}", "hours": "{:.2}", "cards_per_hour": "{:.1}"}}"#,
+            added, removed, hours, rate
+        )
+    }
+
+    /// How many cards are in the collection and what they're worth
+    pub(crate) fn totals(&self) -> Totals {
+        let scryrs = card_store::cards();
+        let mut totals = Totals::default();
+        for holding in self.holdings() {
+            // Printings that don't resolve anymore count as unpriced, `unresolved` reports those
+            let price = scryrs
+                .get_card_by_id(&holding.key.scryfall_id)
+                .and_then(|card| PRICING.card_price(card, holding.variant.finish));
+            totals.add(holding.count, price);
+        }
+        totals
+    }
+
+    pub(crate) fn history(&self, filter: &HistoryFilter) -> (Totals, String, String) {
+        let totals = self.totals();
 
-        let mut seen_files = HashSet::new();
+        let mut seen_keys = HashSet::new();
         let cards = {
-            let scryrs = crate::search::CARDS.lock().unwrap();
-            self.history
+            let scryrs = card_store::cards();
+            self.filtered_history(filter, Some(120))
                 .iter()
-                .rev()
-                .take(120)
-                .filter(|history_entry| seen_files.insert(history_entry.file_name.clone()))
+                .filter(|history_entry| seen_keys.insert(history_entry.key.clone()))
                 .take(60)
-                .map(|history_entry| {
-                    format!(
-                        r#"{{"uuid": "{}", "non_foil_count": "{}", "foil_count": "{}", "value": "{:.2}"}}"#,
-                        &history_entry.file_name,
-                        self.get(&history_entry.file_name),
-                        self.get_foil(&history_entry.file_name),
-                        scryrs.get_card_by_id(&history_entry.file_name[..history_entry.file_name.rfind('-').unwrap()]).unwrap().usd()
-                    )
+                .filter_map(|history_entry| {
+                    let card = scryrs.get_card_by_id(&history_entry.key.scryfall_id)?;
+                    Some(format!(
+                        r#"{{"uuid": "{}", {}, {}}}"#,
+                        scryrs.image(&history_entry.key).unwrap_or_default(),
+                        self.counts_json(&history_entry.key, card),
+                        PRICING.json(PRICING.card_price(card, Finish::NonFoil))
+                    ))
                 })
                 .collect::<Vec<_>>()
                 .join(", ")
         };
 
-        (total_cards, total_value, cards)
+        (totals, cards, self.history_summary(filter))
+    }
+
+    /// Copies of a printing in one finish, across every condition and language
+    pub(crate) fn count(&self, key: &PrintingKey, finish: Finish) -> usize {
+        self.connection
+            .query_row(
+                "SELECT COALESCE(SUM(count), 0) FROM holdings WHERE scryfall_id = ?1 AND face = ?2 AND finish = ?3",
+                params![key.scryfall_id, key.face, finish.as_str()],
+                |row| row.get(0),
+            )
+            .unwrap_or(0)
+    }
+
+    /// Every copy of a printing, whatever the finish or face
+    pub(crate) fn owned(&self, scryfall_id: &str) -> usize {
+        self.connection
+            .query_row(
+                "SELECT COALESCE(SUM(count), 0) FROM holdings WHERE scryfall_id = ?1",
+                params![scryfall_id],
+                |row| row.get(0),
+            )
+            .unwrap_or(0)
+    }
+
+    pub(crate) fn get(&self, key: &PrintingKey) -> usize {
+        self.count(key, Finish::NonFoil)
+    }
+
+    pub(crate) fn get_foil(&self, key: &PrintingKey) -> usize {
+        self.count(key, Finish::Foil)
+    }
+}
+
+/// One change to make with `apply_change`
+struct Change<'a> {
+    key: &'a PrintingKey,
+    change_type: ChangeType,
+    variant: &'a Variant,
+    /// The other collection, for transfers
+    counterpart: Option<&'a str>,
+    /// Links the two sides of a transfer
+    transfer_id: Option<&'a str>,
+}
+
+impl<'a> Change<'a> {
+    fn new(key: &'a PrintingKey, change_type: ChangeType, variant: &'a Variant) -> Self {
+        Self {
+            key,
+            change_type,
+            variant,
+            counterpart: None,
+            transfer_id: None,
+        }
+    }
+}
+
+/// The body of `CardDatabase::change`, for callers that make a lot of changes in one transaction
+fn apply_change(
+    connection: &Connection,
+    collection: &str,
+    session: &Session,
+    change: &Change,
+    update: impl FnOnce(Option<usize>) -> Option<usize>,
+) -> rusqlite::Result<Option<i64>> {
+    let current: Option<usize> = connection
+        .query_row(
+            &format!("SELECT count FROM holdings WHERE {}", VARIANT_MATCH),
+            params_from_iter(variant_params(change.key, change.variant)),
+            |row| row.get(0),
+        )
+        .optional()?;
+    let Some(updated_value) = update(current) else {
+        return Ok(None);
+    };
+
+    set_count(connection, change.key, change.variant, updated_value)?;
+    // The session's target is a container in its own collection, it means nothing in any other
+    let target = session
+        .target
+        .as_ref()
+        .filter(|_| session.collection == collection);
+    let added = updated_value.saturating_sub(current.unwrap_or_default());
+    if let (Some(target), true) = (target, added > 0) {
+        add_to_location(connection, change.key, change.variant, target, added)?;
+    }
+    trim_locations(
+        connection,
+        change.key,
+        change.variant,
+        updated_value,
+        target.map(|target| target.container.id),
+    )?;
+    connection.execute(
+        &format!(
+            "INSERT INTO history (scryfall_id, face, change_type, previous_value, updated_value, foil, {}, created_at, session_id, client_id, counterpart, transfer_id)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)",
+            VARIANT_COLUMNS
+        ),
+        params![
+            change.key.scryfall_id,
+            change.key.face,
+            change.change_type.as_str(),
+            current.unwrap_or_default(),
+            updated_value,
+            change.variant.finish != Finish::NonFoil,
+            change.variant.finish.as_str(),
+            change.variant.condition.as_str(),
+            change.variant.language,
+            change.variant.signed,
+            change.variant.altered,
+            Utc::now().timestamp(),
+            session.id,
+            session.client_id,
+            change.counterpart,
+            change.transfer_id
+        ],
+    )?;
+    let id = connection.last_insert_rowid();
+    // A new change means whatever was undone can't be redone anymore
+    connection.execute(
+        "UPDATE history SET undone = ?1 WHERE undone = ?2",
+        params![DISCARDED, UNDONE],
+    )?;
+    Ok(Some(id))
+}
+
+/// The history entry with this id, if it's in state `undone` and it's the newest change to its variant that's in
+/// effect, so reverting or re-applying it won't clobber anything done since
+fn latest_entry(connection: &Connection, id: i64, undone: usize) -> rusqlite::Result<Option<HistoryEntry>> {
+    let Some(entry) = connection
+        .query_row(
+            &format!("SELECT {} FROM history WHERE id = ?1 AND undone = ?2", HISTORY_COLUMNS),
+            params![id, undone],
+            history_entry_from_row,
+        )
+        .optional()?
+    else {
+        return Ok(None);
+    };
+    let mut params = variant_params(&entry.key, &entry.variant);
+    params.push(Value::from(id));
+    params.push(Value::from(ACTIVE as i64));
+    let newer: bool = connection.query_row(
+        &format!(
+            "SELECT EXISTS (SELECT 1 FROM history WHERE {} AND id > ?8 AND undone = ?9)",
+            VARIANT_MATCH
+        ),
+        params_from_iter(params),
+        |row| row.get(0),
+    )?;
+    Ok((!newer).then_some(entry))
+}
+
+fn set_count(
+    connection: &Connection,
+    key: &PrintingKey,
+    variant: &Variant,
+    value: usize,
+) -> rusqlite::Result<()> {
+    connection.execute(
+        &format!(
+            "INSERT INTO holdings (scryfall_id, face, {}, count) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+             ON CONFLICT (scryfall_id, face, {0}) DO UPDATE SET count = ?8",
+            VARIANT_COLUMNS
+        ),
+        params![
+            key.scryfall_id,
+            key.face,
+            variant.finish.as_str(),
+            variant.condition.as_str(),
+            variant.language,
+            variant.signed,
+            variant.altered,
+            value
+        ],
+    )?;
+    Ok(())
+}
+
+fn container_from_row(row: &rusqlite::Row) -> rusqlite::Result<Container> {
+    Ok(Container {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        kind: ContainerKind::from_str(&row.get::<_, String>(2)?).unwrap_or(ContainerKind::Box),
+    })
+}
+
+/// Copies of a variant that are in a container, or in any container
+fn located_count(
+    connection: &Connection,
+    key: &PrintingKey,
+    variant: &Variant,
+    container_id: Option<i64>,
+) -> rusqlite::Result<usize> {
+    let mut params = variant_params(key, variant);
+    params.push(Value::from(container_id));
+    connection.query_row(
+        &format!(
+            "SELECT COALESCE(SUM(count), 0) FROM locations WHERE {} AND (?8 IS NULL OR container_id = ?8)",
+            VARIANT_MATCH
+        ),
+        params_from_iter(params),
+        |row| row.get(0),
+    )
+}
+
+fn add_to_location(
+    connection: &Connection,
+    key: &PrintingKey,
+    variant: &Variant,
+    target: &Target,
+    count: usize,
+) -> rusqlite::Result<()> {
+    let mut params = variant_params(key, variant);
+    params.extend([
+        Value::from(target.container.id),
+        Value::from(target.position.section as i64),
+        Value::from(target.position.slot as i64),
+        Value::from(count as i64),
+    ]);
+    connection.execute(
+        &format!(
+            "INSERT INTO locations (scryfall_id, face, {}, container_id, section, slot, count)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)
+             ON CONFLICT (scryfall_id, face, {0}, container_id, section, slot) DO UPDATE SET count = count + ?11",
+            VARIANT_COLUMNS
+        ),
+        params_from_iter(params),
+    )?;
+    Ok(())
+}
+
+/// Take copies out of a container (or any container), last position first
+fn take_from_locations(
+    connection: &Connection,
+    key: &PrintingKey,
+    variant: &Variant,
+    container_id: Option<i64>,
+    mut count: usize,
+) -> rusqlite::Result<()> {
+    let rows: Vec<(i64, usize, usize, usize)> = {
+        let mut params = variant_params(key, variant);
+        params.push(Value::from(container_id));
+        let mut statement = connection.prepare_cached(&format!(
+            "SELECT container_id, section, slot, count FROM locations
+             WHERE {} AND (?8 IS NULL OR container_id = ?8) AND count > 0
+             ORDER BY container_id DESC, section DESC, slot DESC",
+            VARIANT_MATCH
+        ))?;
+        let rows = statement
+            .query_map(params_from_iter(params), |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        rows
+    };
+
+    for (container_id, section, slot, located) in rows {
+        if count == 0 {
+            break;
+        }
+        let taken = count.min(located);
+        let remaining = located - taken;
+        let mut params = variant_params(key, variant);
+        params.extend([
+            Value::from(container_id),
+            Value::from(section as i64),
+            Value::from(slot as i64),
+            Value::from(remaining as i64),
+        ]);
+        connection.execute(
+            &format!(
+                "UPDATE locations SET count = ?11 WHERE {} AND container_id = ?8 AND section = ?9 AND slot = ?10",
+                VARIANT_MATCH
+            ),
+            params_from_iter(params),
+        )?;
+        count -= taken;
+    }
+    connection.execute("DELETE FROM locations WHERE count = 0", [])?;
+    Ok(())
+}
+
+/// Make sure no more copies are in containers than we actually have, taking them out of `preferred` first
+fn trim_locations(
+    connection: &Connection,
+    key: &PrintingKey,
+    variant: &Variant,
+    count: usize,
+    preferred: Option<i64>,
+) -> rusqlite::Result<()> {
+    let excess = located_count(connection, key, variant, None)?.saturating_sub(count);
+    if excess == 0 {
+        return Ok(());
+    }
+    if preferred.is_some() {
+        take_from_locations(connection, key, variant, preferred, excess)?;
+    }
+    let excess = located_count(connection, key, variant, None)?.saturating_sub(count);
+    take_from_locations(connection, key, variant, None, excess)
+}
+
+/// Apply any migrations the database is missing, returning the version it was at before
+pub(crate) fn migrate(connection: &mut Connection, migrations: &[&str]) -> rusqlite::Result<usize> {
+    let version: usize = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    for (index, migration) in migrations.iter().enumerate().skip(version) {
+        let transaction = connection.transaction()?;
+        transaction.execute_batch(migration)?;
+        transaction.pragma_update(None, "user_version", index + 1)?;
+        transaction.commit()?;
+    }
+    Ok(version)
+}
+
+/// `database.json` was keyed by image file name, which is where the printing used to come from
+fn json_key(file_name: &str) -> PrintingKey {
+    card_store::image_file_key(file_name).unwrap_or_else(|| PrintingKey::new(file_name))
+}
+
+/// The old `database.json` started out as an empty file, which just means an empty collection
+fn read_json(path: &str) -> Result<JsonDatabase> {
+    let mut contents = String::new();
+    File::open(path)?.read_to_string(&mut contents)?;
+    if contents.trim().is_empty() {
+        return Ok(JsonDatabase::default());
     }
+    Ok(serde_json::from_str(&contents)?)
+}
+
+/// A database that doesn't exist yet is fine, we'll create it
+fn is_valid(path: &str) -> bool {
+    if fs::metadata(path).is_err() {
+        return true;
+    }
+    Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .and_then(|connection| {
+            connection.query_row("PRAGMA quick_check", [], |row| row.get::<_, String>(0))
+        })
+        .is_ok_and(|result| result == "ok")
+}
 
-    pub(crate) fn get(&self, id: &str) -> usize {
-        self.database.get(id).map_or(0, |c| c.non_foil)
+/// Backups of the default collection are `collection-<timestamp>.sqlite`, others are `collection-<name>-<timestamp>.sqlite`
+fn backup_prefix(name: &str) -> String {
+    if name == DEFAULT_COLLECTION {
+        "collection-".to_owned()
+    } else {
+        format!("collection-{}-", name)
     }
+}
+
+/// Backups starting with `prefix`, oldest first
+fn backups(prefix: &str) -> Result<Vec<String>> {
+    let mut backups: Vec<(u64, String)> = fs::read_dir(BACKUP_DIRECTORY)?
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            let timestamp = path
+                .file_name()?
+                .to_str()?
+                .strip_prefix(prefix)?
+                .strip_suffix(".sqlite")?
+                .parse()
+                .ok()?;
+            Some((timestamp, path.to_str()?.to_owned()))
+        })
+        .collect();
+    backups.sort();
+    Ok(backups.into_iter().map(|(_, path)| path).collect())
+}
 
-    pub(crate) fn get_foil(&self, id: &str) -> usize {
-        self.database.get(id).map_or(0, |c| c.foil)
+/// Replace a corrupt database with the newest backup that passes an integrity check
+fn restore_backup(path: &str, prefix: &str) -> Result<()> {
+    eprintln!("{} is corrupt, looking for a backup to restore...", path);
+    let Some(backup_path) = backups(prefix)
+        .unwrap_or_default()
+        .into_iter()
+        .rev()
+        .find(|backup_path| is_valid(backup_path))
+    else {
+        bail!(
+            "{} is corrupt and there's no valid backup in {}",
+            path,
+            BACKUP_DIRECTORY
+        );
+    };
+
+    println!("Restoring {}", backup_path);
+    fs::rename(path, format!("{}.corrupt", path))?;
+    for suffix in ["-wal", "-shm"] {
+        let _ = fs::remove_file(format!("{}{}", path, suffix));
     }
+
+    let temp_path = format!("{}.tmp", path);
+    fs::copy(&backup_path, &temp_path)?;
+    File::open(&temp_path)?.sync_all()?;
+    fs::rename(&temp_path, path)?;
+    Ok(())
 }
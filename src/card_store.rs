@@ -0,0 +1,366 @@
+use crate::update_data::DataDiff;
+
+use scryers::{
+    bulk::{BulkDownload, BulkDownloadType},
+    card::Card,
+};
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, RwLock},
+};
+
+lazy_static::lazy_static! {
+    static ref CARD_STORE: RwLock<Arc<CardStore>> = RwLock::new(Arc::new(CardStore::load("./scryfall.db")));
+}
+
+/// The current card data, cheap to clone and safe to hold onto while doing slow work
+pub(crate) fn cards() -> Arc<CardStore> {
+    CARD_STORE.read().unwrap().clone()
+}
+
+/// Swap in a new store, anyone still holding the old one keeps using it until they drop it
+pub(crate) fn replace(store: CardStore) {
+    *CARD_STORE.write().unwrap() = Arc::new(store);
+}
+
+/// Which printing a copy is, and which face of it was scanned. Collections are keyed by this rather than by
+/// image file names, see `CardStore::image` for those
+#[derive(Serialize, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Debug)]
+pub(crate) struct PrintingKey {
+    pub(crate) scryfall_id: String,
+    /// 0 for the front, 1 for the back of double faced cards
+    pub(crate) face: usize,
+}
+
+impl PrintingKey {
+    /// The front face of a printing
+    pub(crate) fn new(scryfall_id: &str) -> Self {
+        Self {
+            scryfall_id: scryfall_id.to_owned(),
+            face: 0,
+        }
+    }
+}
+
+impl std::fmt::Display for PrintingKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}/{}", self.scryfall_id, self.face)
+    }
+}
+
+/// All of the Scryfall bulk data, loaded once, with lookup tables for the ways we access it
+pub(crate) struct CardStore {
+    bulk: BulkDownload,
+    by_id: HashMap<String, usize>,
+    /// The faces of each printing we have images for, front first
+    id_to_faces: HashMap<String, Vec<PrintingKey>>,
+    images: HashMap<PrintingKey, String>,
+    /// The other way around, for the image file names the UI sends back
+    image_keys: HashMap<String, PrintingKey>,
+    /// Newest printing first
+    oracle_to_ids: HashMap<String, Vec<String>>,
+    set_to_ids: HashMap<String, Vec<String>>,
+    /// Every search token and how many printings use it, so an update can take out the ones nothing uses anymore
+    tokens: HashMap<String, usize>,
+}
+
+impl CardStore {
+    fn load(path: &str) -> Self {
+        let bulk = BulkDownload::new(path, BulkDownloadType::DefaultCards).unwrap();
+
+        let mut tokens = HashMap::new();
+        for card in bulk.cards().iter() {
+            add_card_tokens(&mut tokens, card);
+        }
+
+        Self::index(bulk, image_files(), tokens)
+    }
+
+    /// Build a store from newer bulk data by patching this one with `diff` rather than indexing everything again
+    /// `downloaded` are the printings whose images were just fetched, the rest of `images/` is already known
+    pub(crate) fn updated(
+        &self,
+        bulk: BulkDownload,
+        diff: &DataDiff,
+        downloaded: &[String],
+    ) -> Self {
+        // Every printing's position in the new bulk data can move, so that's the one thing built from scratch
+        let by_id: HashMap<String, usize> = bulk
+            .cards()
+            .iter()
+            .enumerate()
+            .map(|(index, card)| (card.id().to_owned(), index))
+            .collect();
+
+        let mut tokens = self.tokens.clone();
+        let mut oracle_to_ids = self.oracle_to_ids.clone();
+        let mut set_to_ids = self.set_to_ids.clone();
+        // Take out whatever the old versions of changed and removed printings added
+        for id in diff.changed.iter().chain(diff.removed.iter()) {
+            let Some(card) = self.get_card_by_id(id) else {
+                continue;
+            };
+            remove_card_tokens(&mut tokens, card);
+            remove_id(&mut oracle_to_ids, &oracle_key(card), id);
+            remove_id(&mut set_to_ids, card.set(), id);
+        }
+        let mut changed_oracles = HashSet::new();
+        for id in diff.added.iter().chain(diff.changed.iter()) {
+            let card = &bulk.cards()[by_id[id]];
+            add_card_tokens(&mut tokens, card);
+            oracle_to_ids
+                .entry(oracle_key(card))
+                .or_default()
+                .push(id.clone());
+            set_to_ids
+                .entry(card.set().to_owned())
+                .or_default()
+                .push(id.clone());
+            changed_oracles.insert(oracle_key(card));
+        }
+        for oracle in changed_oracles {
+            if let Some(ids) = oracle_to_ids.get_mut(&oracle) {
+                ids.sort_by_cached_key(|id| {
+                    std::cmp::Reverse(bulk.cards()[by_id[id]].released_at().to_owned())
+                });
+            }
+        }
+
+        let mut images = self.images.clone();
+        let mut id_to_faces = self.id_to_faces.clone();
+        let mut image_keys = self.image_keys.clone();
+        for id in downloaded {
+            for face in 0..2 {
+                let key = PrintingKey {
+                    scryfall_id: id.clone(),
+                    face,
+                };
+                let file_name = image_file_name(&key);
+                if std::path::Path::new("./images/").join(&file_name).exists() {
+                    id_to_faces.entry(id.clone()).or_default().push(key.clone());
+                    image_keys.insert(file_name.clone(), key.clone());
+                    images.insert(key, file_name);
+                }
+            }
+        }
+
+        Self {
+            bulk,
+            by_id,
+            id_to_faces,
+            images,
+            image_keys,
+            oracle_to_ids,
+            set_to_ids,
+            tokens,
+        }
+    }
+
+    fn index(
+        bulk: BulkDownload,
+        images: HashMap<PrintingKey, String>,
+        tokens: HashMap<String, usize>,
+    ) -> Self {
+        let mut by_id = HashMap::new();
+        let mut oracle_to_cards: HashMap<String, Vec<&Card>> = HashMap::new();
+        let mut set_to_ids: HashMap<String, Vec<String>> = HashMap::new();
+        for (index, card) in bulk.cards().iter().enumerate() {
+            by_id.insert(card.id().to_owned(), index);
+            oracle_to_cards
+                .entry(oracle_key(card))
+                .or_default()
+                .push(card);
+            set_to_ids
+                .entry(card.set().to_owned())
+                .or_default()
+                .push(card.id().to_owned());
+        }
+
+        // Newest printings first, since that's usually what's being opened
+        let oracle_to_ids = oracle_to_cards
+            .into_iter()
+            .map(|(oracle_id, mut printings)| {
+                printings.sort_by(|a, b| b.released_at().cmp(a.released_at()));
+                (
+                    oracle_id,
+                    printings.iter().map(|card| card.id().to_owned()).collect(),
+                )
+            })
+            .collect();
+
+        let mut id_to_faces: HashMap<String, Vec<PrintingKey>> = HashMap::new();
+        for key in images.keys() {
+            id_to_faces
+                .entry(key.scryfall_id.clone())
+                .or_default()
+                .push(key.clone());
+        }
+        for faces in id_to_faces.values_mut() {
+            faces.sort();
+        }
+        let image_keys = images
+            .iter()
+            .map(|(key, file_name)| (file_name.clone(), key.clone()))
+            .collect();
+
+        Self {
+            bulk,
+            by_id,
+            id_to_faces,
+            images,
+            image_keys,
+            oracle_to_ids,
+            set_to_ids,
+            tokens,
+        }
+    }
+
+    pub(crate) fn cards(&self) -> &[Card] {
+        self.bulk.cards()
+    }
+
+    pub(crate) fn get_card_by_id(&self, id: &str) -> Option<&Card> {
+        self.by_id.get(id).map(|&index| &self.bulk.cards()[index])
+    }
+
+    /// The faces of a printing we have images for, empty if we don't have any
+    pub(crate) fn faces(&self, id: &str) -> &[PrintingKey] {
+        self.id_to_faces.get(id).map_or(&[], Vec::as_slice)
+    }
+
+    /// The image file for a face, relative to `./images/`
+    pub(crate) fn image(&self, key: &PrintingKey) -> Option<&str> {
+        self.images.get(key).map(String::as_str)
+    }
+
+    /// Which face an image file shows
+    pub(crate) fn image_key(&self, file_name: &str) -> Option<&PrintingKey> {
+        self.image_keys.get(file_name)
+    }
+
+    /// Every printing of a card, newest first
+    pub(crate) fn printings(&self, oracle_id: &str) -> &[String] {
+        self.oracle_to_ids.get(oracle_id).map_or(&[], Vec::as_slice)
+    }
+
+    pub(crate) fn set_printings(&self, set: &str) -> &[String] {
+        self.set_to_ids.get(set).map_or(&[], Vec::as_slice)
+    }
+
+    pub(crate) fn is_token(&self, token: &str) -> bool {
+        self.tokens.contains_key(token)
+    }
+}
+
+/// Cards without an oracle id (reversible cards) are grouped on their own id
+pub(crate) fn oracle_key(card: &Card) -> String {
+    card.oracle_id()
+        .clone()
+        .unwrap_or_else(|| card.id().to_owned())
+}
+
+/// Image files are named `<scryfall id>-<face>.jpg`, this is the only place that knows that
+fn image_files() -> HashMap<PrintingKey, String> {
+    let mut images = HashMap::new();
+    for entry in std::fs::read_dir(std::path::Path::new("./images/")).unwrap() {
+        let file_name = entry
+            .unwrap()
+            .path()
+            .file_name()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_owned();
+        if let Some(key) = image_file_key(&file_name) {
+            images.insert(key, file_name);
+        }
+    }
+    images
+}
+
+fn image_file_name(key: &PrintingKey) -> String {
+    format!("{}-{}.jpg", key.scryfall_id, key.face)
+}
+
+pub(crate) fn image_file_key(file_name: &str) -> Option<PrintingKey> {
+    let (scryfall_id, face) = file_name.strip_suffix(".jpg")?.rsplit_once('-')?;
+    Some(PrintingKey {
+        scryfall_id: scryfall_id.to_owned(),
+        face: face.parse().ok()?,
+    })
+}
+
+fn remove_id(ids: &mut HashMap<String, Vec<String>>, group: &str, id: &str) {
+    if let Some(group_ids) = ids.get_mut(group) {
+        group_ids.retain(|group_id| group_id != id);
+        if group_ids.is_empty() {
+            ids.remove(group);
+        }
+    }
+}
+
+fn add_card_tokens(tokens: &mut HashMap<String, usize>, card: &Card) {
+    for token in card_tokens(card) {
+        *tokens.entry(token).or_default() += 1;
+    }
+}
+
+fn remove_card_tokens(tokens: &mut HashMap<String, usize>, card: &Card) {
+    for token in card_tokens(card) {
+        if let Some(count) = tokens.get_mut(&token) {
+            *count -= 1;
+            if *count == 0 {
+                tokens.remove(&token);
+            }
+        }
+    }
+}
+
+/// The words a card adds to the search vocabulary, only English printings count since that's what we OCR
+fn card_tokens(card: &Card) -> HashSet<String> {
+    let mut tokens = HashSet::new();
+    if card.lang() != "en" {
+        return tokens;
+    }
+
+    tokens.extend(
+        card.name()
+            .to_lowercase()
+            .split_whitespace()
+            .map(String::from),
+    );
+    if let Some(text) = card.oracle_text() {
+        tokens.extend(text.to_lowercase().split_whitespace().map(String::from));
+    }
+    if let Some(type_line) = card.type_line() {
+        tokens.extend(
+            type_line
+                .to_lowercase()
+                .split_whitespace()
+                .map(String::from),
+        );
+    }
+    tokens.extend(card.keywords().iter().map(String::from));
+    if let Some(flavor_name) = card.flavor_name() {
+        tokens.extend(
+            flavor_name
+                .to_lowercase()
+                .split_whitespace()
+                .map(String::from),
+        );
+    }
+    if let Some(flavor_text) = card.flavor_text() {
+        tokens.extend(
+            flavor_text
+                .to_lowercase()
+                .split_whitespace()
+                .map(String::from),
+        );
+    }
+
+    if let Some(set_name) = card.set_name() {
+        tokens.extend(set_name.to_lowercase().split_whitespace().map(String::from));
+    }
+    tokens
+}
@@ -0,0 +1,648 @@
+use crate::card_database::{self, CardDatabase, Session, Variant, DEFAULT_COLLECTION};
+use crate::card_store;
+use crate::decks;
+use crate::export::{self, ExportFormat};
+use crate::import::{self, ImportFormat};
+use crate::prices::{self, PriceStore};
+use crate::pricing::PRICING;
+use crate::reports::{self, ReportFormat};
+use crate::sets::{self, ChecklistOptions, ChecklistOrder};
+use crate::trades;
+
+use chrono::{DateTime, NaiveDate, Utc};
+
+/// Run a command line subcommand, returning `false` if `args` isn't one so the server should start instead
+/// Commands work on the default collection unless given `--collection <name>`
+pub(crate) fn run(args: &[String]) -> bool {
+    let mut args = args.to_vec();
+    let collection =
+        take_option(&mut args, "--collection").unwrap_or_else(|| DEFAULT_COLLECTION.to_owned());
+    let command = args.get(1).map(String::as_str);
+    if !matches!(
+        command,
+        Some(
+            "verify"
+                | "rebuild"
+                | "as-of"
+                | "collections"
+                | "import"
+                | "export"
+                | "import-prices"
+                | "value-history"
+                | "movers"
+                | "session-value"
+                | "deck-check"
+                | "report"
+                | "set-completion"
+                | "tradeables"
+        )
+    ) {
+        return false;
+    }
+    if command == Some("collections") {
+        collections();
+        return true;
+    }
+    if command == Some("import-prices") {
+        import_prices(&mut args);
+        return true;
+    }
+    // Price reports include the prices in the current card data
+    if matches!(command, Some("value-history" | "movers" | "session-value")) {
+        prices::snapshot_current();
+    }
+
+    // Importing is the only command that makes sense on a collection that doesn't exist yet
+    let database = if command == Some("import") {
+        card_database::collection(&collection).map(Some)
+    } else {
+        card_database::existing_collection(&collection)
+    };
+    let database = match database {
+        Ok(Some(database)) => database,
+        Ok(None) => {
+            eprintln!("There's no collection named {}", collection);
+            return true;
+        }
+        Err(e) => {
+            eprintln!("Failed to open collection {}: {}", collection, e);
+            return true;
+        }
+    };
+    let mut database = database.lock().unwrap();
+    match command {
+        Some("verify") => verify(&database),
+        Some("rebuild") => rebuild(&mut database),
+        Some("import") => import(&mut args, &mut database),
+        Some("export") => export(&mut args, &database),
+        Some("value-history") => value_history(&database),
+        Some("movers") => movers(&mut args, &database),
+        Some("session-value") => session_value(&database),
+        Some("deck-check") => deck_check(&mut args, &database),
+        Some("report") => report(&mut args, &database),
+        Some("set-completion") => set_completion(&mut args, &database),
+        Some("tradeables") => tradeables(&mut args, &database),
+        _ => match args.get(2).and_then(|date| parse_date(date)) {
+            Some(date) => as_of(&database, date),
+            None => println!("Usage: gathering_the_magic as-of <YYYY-MM-DD or RFC 3339 time>"),
+        },
+    }
+    true
+}
+
+/// Pull `--name <value>` out of the arguments, leaving the rest in place
+fn take_option(args: &mut Vec<String>, name: &str) -> Option<String> {
+    let index = args.iter().position(|arg| arg == name)?;
+    args.remove(index);
+    (index < args.len()).then(|| args.remove(index))
+}
+
+/// Pull `--name` out of the arguments, returning whether it was there
+fn take_flag(args: &mut Vec<String>, name: &str) -> bool {
+    let found = args.iter().any(|arg| arg == name);
+    args.retain(|arg| arg != name);
+    found
+}
+
+/// Dates are the end of that day in UTC, so `as-of 2024-05-01` includes everything added on the first
+pub(crate) fn parse_date(date: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(date)
+        .map(|date| date.with_timezone(&Utc))
+        .ok()
+        .or_else(|| {
+            NaiveDate::parse_from_str(date, "%Y-%m-%d")
+                .ok()?
+                .and_hms_opt(23, 59, 59)
+                .map(|date| date.and_utc())
+        })
+}
+
+fn verify(database: &CardDatabase) {
+    let unresolved = database.unresolved();
+    if !unresolved.is_empty() {
+        println!(
+            "{} printings don't match any card or image:",
+            unresolved.len()
+        );
+        for holding in unresolved {
+            println!(
+                "  {} {} ({} copies)",
+                holding.key,
+                variant_label(&holding.variant),
+                holding.count
+            );
+        }
+    }
+
+    match database.verify() {
+        Ok(drift) if drift.is_empty() => println!("The collection matches its history."),
+        Ok(drift) => {
+            println!("{} variants don't match their history:", drift.len());
+            println!(
+                "{:<45} {:<30} {:>7} {:>7}",
+                "card", "variant", "stored", "history"
+            );
+            for card in drift {
+                println!(
+                    "{:<45} {:<30} {:>7} {:>7}",
+                    card.key.to_string(),
+                    variant_label(&card.variant),
+                    card.snapshot,
+                    card.replayed
+                );
+            }
+            println!("Run `rebuild` to replace the stored counts with the history.");
+        }
+        Err(e) => eprintln!("Failed to verify the collection: {}", e),
+    }
+}
+
+/// `import <file> [--format <format>] [--dry-run]`, the format is worked out from the file if it isn't given
+fn import(args: &mut Vec<String>, database: &mut CardDatabase) {
+    let dry_run = take_flag(args, "--dry-run");
+    let format = match take_option(args, "--format") {
+        Some(format) => match ImportFormat::from_str(&format) {
+            Some(format) => Some(format),
+            None => {
+                println!("Unknown format {}, use moxfield, archidekt, deckbox, manabox, tcgplayer, delver or text", format);
+                return;
+            }
+        },
+        None => None,
+    };
+    let Some(path) = args.get(2) else {
+        println!("Usage: gathering_the_magic import <file> [--format <format>] [--dry-run] [--collection <name>]");
+        return;
+    };
+
+    let session = Session::new("command-line");
+    match import::import(path, format, dry_run, &session, database) {
+        Ok(report) => {
+            report.print(dry_run);
+            if !report.unresolved.is_empty() {
+                let report_path = report.unresolved_path(path);
+                match report.write_unresolved(&report_path) {
+                    Ok(()) => println!("The unresolved rows were written to {}", report_path),
+                    Err(e) => eprintln!("Failed to write {}: {}", report_path, e),
+                }
+            }
+        }
+        Err(e) => eprintln!("Failed to import {}: {}", path, e),
+    }
+}
+
+/// `deck-check <file> [--deck <name>]`, where `--deck` is the deck container the list is being built in
+fn deck_check(args: &mut Vec<String>, database: &CardDatabase) {
+    let deck = take_option(args, "--deck");
+    let Some(path) = args.get(2) else {
+        println!("Usage: gathering_the_magic deck-check <decklist> [--deck <name>] [--collection <name>]");
+        return;
+    };
+    match decks::check(path, deck.as_deref(), database) {
+        Ok(report) => report.print(),
+        Err(e) => eprintln!("Failed to check {}: {}", path, e),
+    }
+}
+
+/// `report [--top <n>] [--format <table|csv|json>] [--output <file>]`, a table on stdout by default
+fn report(args: &mut Vec<String>, database: &CardDatabase) {
+    let output = take_option(args, "--output");
+    let top = match take_option(args, "--top").map(|top| top.parse()) {
+        Some(Ok(top)) => top,
+        Some(Err(_)) => {
+            println!("--top takes a number");
+            return;
+        }
+        None => reports::TOP_CARDS,
+    };
+    let format = match take_option(args, "--format") {
+        Some(format) => match ReportFormat::from_str(&format) {
+            Some(format) => format,
+            None => {
+                println!("Unknown format {}, use table, csv or json", format);
+                return;
+            }
+        },
+        None => ReportFormat::Table,
+    };
+
+    let contents = match reports::report(database, top).write(format) {
+        Ok(contents) => contents,
+        Err(e) => {
+            eprintln!("Failed to write the report: {}", e);
+            return;
+        }
+    };
+    match output {
+        Some(path) => match std::fs::write(&path, contents) {
+            Ok(()) => println!("Wrote the report to {}", path),
+            Err(e) => eprintln!("Failed to write {}: {}", path, e),
+        },
+        None => print!("{}", contents),
+    }
+}
+
+/// `set-completion <set> [--variants] [--showcase] [--tokens] [--sort number|price] [--wantlist <file>]`
+fn set_completion(args: &mut Vec<String>, database: &CardDatabase) {
+    let options = ChecklistOptions {
+        variants: take_flag(args, "--variants"),
+        showcase: take_flag(args, "--showcase"),
+        tokens: take_flag(args, "--tokens"),
+    };
+    let wantlist = take_option(args, "--wantlist");
+    let order = match take_option(args, "--sort") {
+        Some(sort) => match ChecklistOrder::from_str(&sort) {
+            Some(order) => order,
+            None => {
+                println!("Unknown sort {}, use number or price", sort);
+                return;
+            }
+        },
+        None => ChecklistOrder::CollectorNumber,
+    };
+    let Some(set) = args.get(2) else {
+        println!("Usage: gathering_the_magic set-completion <set code> [--variants] [--showcase] [--tokens] [--sort number|price] [--wantlist <file>] [--collection <name>]");
+        return;
+    };
+    let Some(checklist) = sets::checklist(set, options, database) else {
+        println!("There's no set {} in the card data.", set);
+        return;
+    };
+
+    let (cost, unpriced) = checklist.cost_to_complete();
+    println!(
+        "{} ({}): {} of {} ({:.1}%), {} to complete{}",
+        checklist.name,
+        checklist.set,
+        checklist.owned(),
+        checklist.cards.len(),
+        checklist.percent(),
+        PRICING.format(cost),
+        if unpriced > 0 {
+            format!(" plus {} unpriced", unpriced)
+        } else {
+            String::new()
+        }
+    );
+    for card in checklist.missing(order) {
+        println!(
+            "{:<6} {:<40} {:>10}",
+            card.collector_number,
+            card.name,
+            card.price
+                .map_or("no price".to_owned(), |price| PRICING.format(price.amount))
+        );
+    }
+    if let Some(path) = wantlist {
+        match std::fs::write(&path, checklist.wantlist(order)) {
+            Ok(()) => println!("Wrote the missing cards to {}", path),
+            Err(e) => eprintln!("Failed to write {}: {}", path, e),
+        }
+    }
+}
+
+/// `tradeables [--keep <n|constructed|cube>] [--havelist <file>] [--wants <partner's want list>]`
+fn tradeables(args: &mut Vec<String>, database: &CardDatabase) {
+    let keep = match take_option(args, "--keep") {
+        Some(keep) => match trades::keep_threshold(&keep) {
+            Some(keep) => keep,
+            None => {
+                println!("--keep takes a number, constructed or cube");
+                return;
+            }
+        },
+        None => trades::KEEP_COPIES,
+    };
+    let have_list = take_option(args, "--havelist");
+    let wants = take_option(args, "--wants");
+
+    let groups = trades::tradeables(database, keep);
+    println!("Keeping {} of each card:", keep);
+    for group in groups.iter() {
+        println!(
+            "{:>4} of {:>4}  {:<40} {:>10}",
+            group.extras(),
+            group.owned,
+            group.name,
+            PRICING.format(group.value())
+        );
+        for tradeable in group.tradeables.iter() {
+            println!(
+                "      {:>4} {:<6} {:<6} {:<30} {:>10}",
+                tradeable.count,
+                tradeable.set,
+                tradeable.collector_number,
+                variant_label(&tradeable.variant),
+                tradeable
+                    .price
+                    .map_or("no price".to_owned(), |price| PRICING.format(price.amount))
+            );
+        }
+    }
+    println!(
+        "{} extra copies worth {}",
+        groups.iter().map(trades::TradeGroup::extras).sum::<usize>(),
+        PRICING.format(groups.iter().map(trades::TradeGroup::value).sum())
+    );
+
+    if let Some(path) = have_list {
+        match std::fs::write(&path, trades::have_list(&groups)) {
+            Ok(()) => println!("Wrote the have list to {}", path),
+            Err(e) => eprintln!("Failed to write {}: {}", path, e),
+        }
+    }
+    if let Some(path) = wants {
+        match trades::match_wants(&path, &groups) {
+            Ok((matches, unresolved)) => {
+                println!(
+                    "{} cards on {} can come from the extras:",
+                    matches.len(),
+                    path
+                );
+                for found in matches.iter() {
+                    println!(
+                        "{:>4} of {:>4} wanted  {}",
+                        found.count(),
+                        found.wanted,
+                        found.group.name
+                    );
+                }
+                for row in unresolved {
+                    println!("  line {}: {} ({})", row.line, row.text, row.reason);
+                }
+            }
+            Err(e) => eprintln!("Failed to read {}: {}", path, e),
+        }
+    }
+}
+
+/// `export <format> [--output <file>]`, printing to stdout without `--output`
+fn export(args: &mut Vec<String>, database: &CardDatabase) {
+    let output = take_option(args, "--output");
+    let Some(format) = args
+        .get(2)
+        .and_then(|format| ExportFormat::from_str(format))
+    else {
+        println!("Usage: gathering_the_magic export <moxfield|archidekt|deckbox|tcgplayer|mtga|json> [--output <file>] [--collection <name>]");
+        return;
+    };
+
+    let contents = match export::export(format, database) {
+        Ok(contents) => contents,
+        Err(e) => {
+            eprintln!("Failed to export the collection: {}", e);
+            return;
+        }
+    };
+    match output {
+        Some(path) => match std::fs::write(&path, contents) {
+            Ok(()) => println!("Exported the collection to {}", path),
+            Err(e) => eprintln!("Failed to write {}: {}", path, e),
+        },
+        None => print!("{}", contents),
+    }
+}
+
+/// `import-prices <default_cards file> [--date <YYYY-MM-DD>]`, dated by when the file was written if not given
+fn import_prices(args: &mut Vec<String>) {
+    let date = take_option(args, "--date");
+    let date = match date
+        .as_deref()
+        .map(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d"))
+    {
+        Some(Ok(date)) => Some(date),
+        Some(Err(_)) => {
+            println!("Dates are YYYY-MM-DD");
+            return;
+        }
+        None => None,
+    };
+    let Some(path) = args.get(2) else {
+        println!("Usage: gathering_the_magic import-prices <default_cards.json path> [--date <YYYY-MM-DD>]");
+        return;
+    };
+    match prices::import_bulk(path, date) {
+        Ok((date, recorded)) => println!("Recorded {} prices for {}.", recorded, date),
+        Err(e) => eprintln!("Failed to import prices from {}: {}", path, e),
+    }
+}
+
+fn value_history(database: &CardDatabase) {
+    let values =
+        match PriceStore::open().and_then(|store| Ok(prices::value_over_time(database, &store)?)) {
+            Ok(values) => values,
+            Err(e) => {
+                eprintln!("Failed to work out the collection's value: {}", e);
+                return;
+            }
+        };
+    println!(
+        "{:<12} {:>8} {:>12} {:>9}",
+        "date",
+        "cards",
+        format!("value ({})", PRICING.currency().as_str()),
+        "unpriced"
+    );
+    for (date, totals) in values {
+        println!(
+            "{:<12} {:>8} {:>12.2} {:>9}",
+            date, totals.cards, totals.value, totals.unpriced
+        );
+    }
+}
+
+/// How many of the biggest gainers and losers `movers` shows
+const MOVERS_SHOWN: usize = 10;
+
+/// `movers [--since <YYYY-MM-DD>]`, from the oldest snapshot if not given to the newest one
+fn movers(args: &mut Vec<String>, database: &CardDatabase) {
+    let since = take_option(args, "--since");
+    let store = match PriceStore::open() {
+        Ok(store) => store,
+        Err(e) => {
+            eprintln!("Failed to open the price store: {}", e);
+            return;
+        }
+    };
+    let dates = store.dates();
+    let (Some(&first), Some(&last)) = (dates.first(), dates.last()) else {
+        println!("No prices have been recorded yet.");
+        return;
+    };
+    let from = match since {
+        Some(since) => match NaiveDate::parse_from_str(&since, "%Y-%m-%d") {
+            Ok(since) => since,
+            Err(_) => {
+                println!("Dates are YYYY-MM-DD");
+                return;
+            }
+        },
+        None => first,
+    };
+
+    let (movers, unpriced) = prices::movers(database, &store.history(), from, last);
+    println!(
+        "Price changes from {} to {} in {}:",
+        from,
+        last,
+        PRICING.currency().as_str()
+    );
+    let print = |title: &str, movers: &mut dyn Iterator<Item = &prices::Mover>| {
+        println!("{}", title);
+        for mover in movers {
+            println!(
+                "{:>4}  {:<40} {:<6} {:<8} {:>9.2} -> {:>9.2} {:>+10.2}  {}",
+                mover.count,
+                mover.name,
+                mover.set,
+                mover.finish.as_str(),
+                mover.before.amount,
+                mover.after.amount,
+                mover.change(),
+                mover.after.source.label()
+            );
+        }
+    };
+    print(
+        "Gainers:",
+        &mut movers
+            .iter()
+            .filter(|mover| mover.change() > 0.0)
+            .take(MOVERS_SHOWN),
+    );
+    print(
+        "Losers:",
+        &mut movers
+            .iter()
+            .rev()
+            .filter(|mover| mover.change() < 0.0)
+            .take(MOVERS_SHOWN),
+    );
+    if unpriced > 0 {
+        println!("{} copies couldn't be priced on both dates.", unpriced);
+    }
+}
+
+fn session_value(database: &CardDatabase) {
+    let store = match PriceStore::open() {
+        Ok(store) => store,
+        Err(e) => {
+            eprintln!("Failed to open the price store: {}", e);
+            return;
+        }
+    };
+    let cards = card_store::cards();
+    println!(
+        "{:<26} {:<20} {:<26} {:>7} {:>10} {:>9}",
+        "session",
+        "client",
+        "started",
+        "added",
+        format!("value ({})", PRICING.currency().as_str()),
+        "unpriced"
+    );
+    for session in prices::session_values(database, &store.history(), &cards) {
+        println!(
+            "{:<26} {:<20} {:<26} {:>7} {:>10.2} {:>9}",
+            session.session_id.as_deref().unwrap_or("(before sessions)"),
+            session.client_id.as_deref().unwrap_or(""),
+            session
+                .started_at
+                .and_then(|started_at| DateTime::from_timestamp(started_at, 0))
+                .map_or(String::new(), |started_at| started_at.to_rfc3339()),
+            session.added,
+            session.value,
+            session.unpriced
+        );
+    }
+}
+
+fn rebuild(database: &mut CardDatabase) {
+    match database.rebuild() {
+        Ok(cards) => println!("Rebuilt {} cards from the history.", cards),
+        Err(e) => eprintln!("Failed to rebuild the collection: {}", e),
+    }
+}
+
+fn as_of(database: &CardDatabase, date: DateTime<Utc>) {
+    let holdings = match database.replay(Some(date)) {
+        Ok(holdings) => holdings,
+        Err(e) => {
+            eprintln!("Failed to replay the history: {}", e);
+            return;
+        }
+    };
+
+    let cards = card_store::cards();
+    let mut lines: Vec<(String, String, String, usize)> = holdings
+        .into_iter()
+        .filter(|(_, count)| *count > 0)
+        .map(|((key, variant), count)| {
+            let (name, set) = cards
+                .get_card_by_id(&key.scryfall_id)
+                .map_or((key.to_string(), String::new()), |card| {
+                    (card.name().to_owned(), card.set().to_uppercase())
+                });
+            (name, set, variant_label(&variant), count)
+        })
+        .collect();
+    lines.sort();
+
+    println!("Collection as of {}:", date.to_rfc3339());
+    for (name, set, variant, count) in lines.iter() {
+        println!("{:>4}  {} ({}) {}", count, name, set, variant);
+    }
+    println!(
+        "{} cards",
+        lines.iter().map(|(_, _, _, count)| count).sum::<usize>()
+    );
+}
+
+/// Every collection's size and value, and the totals across all of them
+/// Cards without any price are counted separately rather than as being worth nothing
+fn collections() {
+    let (mut total_cards, mut total_value, mut total_unpriced) = (0, 0.0, 0);
+    println!(
+        "{:<20} {:>8} {:>12} {:>9}",
+        "collection",
+        "cards",
+        format!("value ({})", PRICING.currency().as_str()),
+        "unpriced"
+    );
+    for name in card_database::collection_names() {
+        match card_database::collection(&name) {
+            Ok(database) => {
+                let totals = database.lock().unwrap().totals();
+                println!(
+                    "{:<20} {:>8} {:>12.2} {:>9}",
+                    name, totals.cards, totals.value, totals.unpriced
+                );
+                total_cards += totals.cards;
+                total_value += totals.value;
+                total_unpriced += totals.unpriced;
+            }
+            Err(e) => eprintln!("Failed to open collection {}: {}", name, e),
+        }
+    }
+    println!(
+        "{:<20} {:>8} {:>12.2} {:>9}",
+        "total", total_cards, total_value, total_unpriced
+    );
+}
+
+/// `foil NM en`, plus `signed`/`altered` when they apply
+fn variant_label(variant: &Variant) -> String {
+    let mut label = format!(
+        "{} {} {}",
+        variant.finish.as_str(),
+        variant.condition.as_str(),
+        variant.language
+    );
+    if variant.signed {
+        label.push_str(" signed");
+    }
+    if variant.altered {
+        label.push_str(" altered");
+    }
+    label
+}
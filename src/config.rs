@@ -0,0 +1,14 @@
+use serde::de::DeserializeOwned;
+
+/// Settings from a JSON file in the working directory (next to `collection.sqlite`), with anything it leaves out
+/// taken from the defaults. No file means all defaults, but a file that doesn't parse is reported rather than
+/// quietly ignored, since otherwise a typo looks like the settings having no effect
+pub(crate) fn load_config<T: DeserializeOwned + Default>(path: &str) -> T {
+    let Ok(file) = std::fs::File::open(path) else {
+        return T::default();
+    };
+    serde_json::from_reader(std::io::BufReader::new(file)).unwrap_or_else(|e| {
+        eprintln!("Ignoring {}, it isn't valid: {}", path, e);
+        T::default()
+    })
+}
@@ -0,0 +1,229 @@
+use crate::card_database::{CardDatabase, ContainerKind, Finish, Location};
+use crate::card_store::{self, oracle_key, CardStore, PrintingKey};
+use crate::import::{self, Unresolved};
+use crate::pricing::{Price, PRICING};
+
+use anyhow::Result;
+use std::{collections::HashMap, fs};
+
+/// One card in a decklist and how many of it we have, across every printing
+pub(crate) struct DeckLine {
+    pub(crate) name: String,
+    pub(crate) needed: usize,
+    pub(crate) owned: usize,
+    /// Copies already put away in other decks, by deck name
+    pub(crate) committed: Vec<(String, usize)>,
+    /// The cheapest paper printing's current price, for working out the cost of the missing copies
+    pub(crate) price: Option<Price>,
+}
+
+impl DeckLine {
+    pub(crate) fn missing(&self) -> usize {
+        self.needed.saturating_sub(self.owned)
+    }
+
+    /// Copies we own that aren't already in another deck
+    pub(crate) fn free(&self) -> usize {
+        let committed: usize = self.committed.iter().map(|(_, count)| count).sum();
+        self.owned.saturating_sub(committed)
+    }
+
+    /// We have enough copies, but some of them would have to come out of another deck
+    pub(crate) fn is_contested(&self) -> bool {
+        self.missing() == 0 && self.free() < self.needed
+    }
+}
+
+pub(crate) struct DeckReport {
+    /// In decklist order, with cards listed more than once (main deck and sideboard) added together
+    pub(crate) lines: Vec<DeckLine>,
+    pub(crate) unresolved: Vec<Unresolved>,
+}
+
+impl DeckReport {
+    pub(crate) fn needed(&self) -> usize {
+        self.lines.iter().map(|line| line.needed).sum()
+    }
+
+    pub(crate) fn missing(&self) -> usize {
+        self.lines.iter().map(DeckLine::missing).sum()
+    }
+
+    /// What the missing copies cost, and how many of them have no price
+    pub(crate) fn cost_to_complete(&self) -> (f64, usize) {
+        let mut cost = 0.0;
+        let mut unpriced = 0;
+        for line in self.lines.iter().filter(|line| line.missing() > 0) {
+            match line.price {
+                Some(price) => cost += price.amount * line.missing() as f64,
+                None => unpriced += line.missing(),
+            }
+        }
+        (cost, unpriced)
+    }
+
+    pub(crate) fn print(&self) {
+        println!(
+            "{:>4} {:>6} {:>8}  {:<40} {:>10}  notes",
+            "need", "owned", "missing", "card", "price"
+        );
+        for line in self.lines.iter() {
+            let mut notes = vec![];
+            if line.missing() > 0 {
+                notes.push(
+                    line.price
+                        .map_or("no price".to_owned(), |price| price.source.label()),
+                );
+            }
+            // Only worth mentioning when we'd have to take them out
+            if line.free() < line.needed {
+                for (deck, count) in line.committed.iter() {
+                    notes.push(format!("{} in {}", count, deck));
+                }
+            }
+            println!(
+                "{:>4} {:>6} {:>8}  {:<40} {:>10}  {}",
+                line.needed,
+                line.owned,
+                line.missing(),
+                line.name,
+                line.price
+                    .map_or(String::new(), |price| PRICING.format(price.amount)),
+                notes.join(" ")
+            );
+        }
+
+        let (cost, unpriced) = self.cost_to_complete();
+        println!(
+            "Own {} of {} cards, {} missing. Cost to complete: {}{}",
+            self.needed() - self.missing(),
+            self.needed(),
+            self.missing(),
+            PRICING.format(cost),
+            if unpriced > 0 {
+                format!(" plus {} unpriced", unpriced)
+            } else {
+                String::new()
+            }
+        );
+        let contested = self.lines.iter().filter(|line| line.is_contested()).count();
+        if contested > 0 {
+            println!(
+                "{} cards are only complete by taking copies out of other decks.",
+                contested
+            );
+        }
+        if !self.unresolved.is_empty() {
+            println!(
+                "{} lines couldn't be matched to a card:",
+                self.unresolved.len()
+            );
+            for row in self.unresolved.iter() {
+                println!("  line {}: {} ({})", row.line, row.text, row.reason);
+            }
+        }
+    }
+}
+
+/// Check a decklist against the collection by card name, so any printing of a card counts
+/// Copies in the deck container named `deck` (the one being built from the list) aren't counted as committed
+pub(crate) fn check(path: &str, deck: Option<&str>, database: &CardDatabase) -> Result<DeckReport> {
+    let contents = fs::read_to_string(path)?;
+    let cards = card_store::cards();
+    let oracles = oracle_names(&cards);
+
+    let mut report = DeckReport {
+        lines: vec![],
+        unresolved: vec![],
+    };
+    let mut line_oracles: Vec<String> = vec![];
+    for (line, text, quantity, name) in import::read_decklist(&contents) {
+        let Some(oracle) = oracles.get(&name.to_lowercase()) else {
+            report.unresolved.push(Unresolved {
+                line,
+                text,
+                reason: format!("no card named `{}`", name),
+            });
+            continue;
+        };
+        match line_oracles.iter().position(|seen| seen == oracle) {
+            Some(index) => report.lines[index].needed += quantity,
+            None => {
+                line_oracles.push(oracle.clone());
+                report
+                    .lines
+                    .push(oracle_line(oracle, quantity, deck, &cards, database));
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Lowercase names (and front face names of double faced cards) to oracle keys
+pub(crate) fn oracle_names(cards: &CardStore) -> HashMap<String, String> {
+    let mut names = HashMap::new();
+    for card in cards.cards() {
+        let name = card.name().to_lowercase();
+        if let Some((front, _)) = name.split_once(" // ") {
+            names
+                .entry(front.to_owned())
+                .or_insert_with(|| oracle_key(card));
+        }
+        names.entry(name).or_insert_with(|| oracle_key(card));
+    }
+    names
+}
+
+/// Where copies of a printing are put away in decks, other than the deck named `except`
+/// Those copies are spoken for, so they can't go into another deck or a trade
+pub(crate) fn deck_locations(
+    key: &PrintingKey,
+    except: Option<&str>,
+    database: &CardDatabase,
+) -> Vec<Location> {
+    database
+        .locations(key)
+        .into_iter()
+        .filter(|location| {
+            location.container.kind == ContainerKind::Deck
+                && Some(location.container.name.as_str()) != except
+        })
+        .collect()
+}
+
+fn oracle_line(
+    oracle: &str,
+    needed: usize,
+    deck: Option<&str>,
+    cards: &CardStore,
+    database: &CardDatabase,
+) -> DeckLine {
+    let printings = cards.printings(oracle);
+    let mut committed: Vec<(String, usize)> = vec![];
+    for key in printings.iter().flat_map(|id| cards.faces(id)) {
+        for location in deck_locations(key, deck, database) {
+            match committed
+                .iter_mut()
+                .find(|(name, _)| *name == location.container.name)
+            {
+                Some((_, count)) => *count += location.count,
+                None => committed.push((location.container.name, location.count)),
+            }
+        }
+    }
+
+    let name = printings
+        .first()
+        .and_then(|id| cards.get_card_by_id(id))
+        .map_or(oracle.to_owned(), |card| card.name().to_owned());
+    DeckLine {
+        name,
+        needed,
+        owned: printings.iter().map(|id| database.owned(id)).sum(),
+        committed,
+        price: PRICING.cheapest(
+            printings.iter().filter_map(|id| cards.get_card_by_id(id)),
+            Finish::NonFoil,
+        ),
+    }
+}
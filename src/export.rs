@@ -0,0 +1,349 @@
+use crate::card_database::{CardDatabase, Condition, Finish, Variant};
+use crate::card_store;
+
+use anyhow::Result;
+use serde_json::json;
+use std::collections::HashMap;
+
+/// The formats we can write the collection out as
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum ExportFormat {
+    Moxfield,
+    Archidekt,
+    Deckbox,
+    Tcgplayer,
+    /// `4 Lightning Bolt (M10) 146` lines, one per printing
+    Mtga,
+    /// One object per printing and variant, with the card's identity spelled out
+    Json,
+}
+
+impl ExportFormat {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            ExportFormat::Moxfield => "moxfield",
+            ExportFormat::Archidekt => "archidekt",
+            ExportFormat::Deckbox => "deckbox",
+            ExportFormat::Tcgplayer => "tcgplayer",
+            ExportFormat::Mtga => "mtga",
+            ExportFormat::Json => "json",
+        }
+    }
+
+    pub(crate) fn from_str(format: &str) -> Option<Self> {
+        match format.to_lowercase().as_str() {
+            "moxfield" => Some(ExportFormat::Moxfield),
+            "archidekt" => Some(ExportFormat::Archidekt),
+            "deckbox" => Some(ExportFormat::Deckbox),
+            "tcgplayer" => Some(ExportFormat::Tcgplayer),
+            "mtga" | "text" => Some(ExportFormat::Mtga),
+            "json" => Some(ExportFormat::Json),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Mtga => "txt",
+            ExportFormat::Json => "json",
+            _ => "csv",
+        }
+    }
+
+    pub(crate) fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Mtga => "text/plain; charset=utf-8",
+            ExportFormat::Json => "application/json",
+            _ => "text/csv; charset=utf-8",
+        }
+    }
+}
+
+/// A printing and variant we have copies of, with everything the formats want to know about the card
+struct ExportRow {
+    scryfall_id: String,
+    name: String,
+    set: String,
+    set_name: String,
+    collector_number: String,
+    finish: Finish,
+    condition: Condition,
+    language: String,
+    signed: bool,
+    altered: bool,
+    quantity: usize,
+}
+
+/// Write out everything in the collection in `format`
+pub(crate) fn export(format: ExportFormat, database: &CardDatabase) -> Result<String> {
+    let rows = rows(database);
+    match format {
+        ExportFormat::Json => Ok(serde_json::to_string_pretty(&json_rows(&rows))?),
+        ExportFormat::Mtga => Ok(mtga(&rows)),
+        _ => csv(format, &rows),
+    }
+}
+
+/// Holdings are per face, so copies of the same printing filed under either face are added together here
+fn rows(database: &CardDatabase) -> Vec<ExportRow> {
+    let cards = card_store::cards();
+    let mut copies: HashMap<(String, Variant), usize> = HashMap::new();
+    for holding in database.holdings() {
+        if holding.count == 0 {
+            continue;
+        }
+        *copies
+            .entry((holding.key.scryfall_id, holding.variant))
+            .or_default() += holding.count;
+    }
+
+    let mut rows: Vec<ExportRow> = copies
+        .into_iter()
+        .filter_map(|((id, variant), quantity)| {
+            let Some(card) = cards.get_card_by_id(&id) else {
+                eprintln!(
+                    "Skipping {} copies of {}, it isn't in the card data",
+                    quantity, id
+                );
+                return None;
+            };
+            Some(ExportRow {
+                scryfall_id: id,
+                name: card.name().to_owned(),
+                set: card.set().to_owned(),
+                set_name: card.set_name().clone().unwrap_or_default(),
+                collector_number: card.collector_number().to_owned(),
+                finish: variant.finish,
+                condition: variant.condition,
+                language: variant.language,
+                signed: variant.signed,
+                altered: variant.altered,
+                quantity,
+            })
+        })
+        .collect();
+    rows.sort_by(|a, b| {
+        (&a.name, &a.set, &a.collector_number).cmp(&(&b.name, &b.set, &b.collector_number))
+    });
+    rows
+}
+
+fn json_rows(rows: &[ExportRow]) -> serde_json::Value {
+    rows.iter()
+        .map(|row| {
+            json!({
+                "scryfall_id": row.scryfall_id,
+                "name": row.name,
+                "set": row.set,
+                "collector_number": row.collector_number,
+                "finish": row.finish.as_str(),
+                "condition": row.condition.as_str(),
+                "language": row.language,
+                "signed": row.signed,
+                "altered": row.altered,
+                "quantity": row.quantity,
+            })
+        })
+        .collect()
+}
+
+fn csv(format: ExportFormat, rows: &[ExportRow]) -> Result<String> {
+    let mut writer = csv::Writer::from_writer(vec![]);
+    match format {
+        ExportFormat::Moxfield => {
+            writer.write_record([
+                "Count",
+                "Tradelist Count",
+                "Name",
+                "Edition",
+                "Condition",
+                "Language",
+                "Foil",
+                "Collector Number",
+                "Alter",
+                "Signed",
+            ])?;
+            for row in rows {
+                writer.write_record([
+                    row.quantity.to_string().as_str(),
+                    "0",
+                    &row.name,
+                    &row.set,
+                    condition_name(row.condition),
+                    language_name(&row.language),
+                    match row.finish {
+                        Finish::NonFoil => "",
+                        Finish::Foil => "foil",
+                        Finish::Etched => "etched",
+                    },
+                    &row.collector_number,
+                    yes_or_empty(row.altered, "TRUE"),
+                    yes_or_empty(row.signed, "TRUE"),
+                ])?;
+            }
+        }
+        ExportFormat::Archidekt => {
+            writer.write_record([
+                "Quantity",
+                "Name",
+                "Finish",
+                "Condition",
+                "Language",
+                "Edition Name",
+                "Edition Code",
+                "Collector Number",
+                "Scryfall ID",
+            ])?;
+            for row in rows {
+                writer.write_record([
+                    row.quantity.to_string().as_str(),
+                    &row.name,
+                    match row.finish {
+                        Finish::NonFoil => "Normal",
+                        Finish::Foil => "Foil",
+                        Finish::Etched => "Etched",
+                    },
+                    row.condition.as_str(),
+                    &row.language.to_uppercase(),
+                    &row.set_name,
+                    &row.set,
+                    &row.collector_number,
+                    &row.scryfall_id,
+                ])?;
+            }
+        }
+        ExportFormat::Deckbox => {
+            writer.write_record([
+                "Count",
+                "Tradelist Count",
+                "Name",
+                "Edition",
+                "Card Number",
+                "Condition",
+                "Language",
+                "Foil",
+                "Signed",
+                "Altered Art",
+            ])?;
+            for row in rows {
+                writer.write_record([
+                    row.quantity.to_string().as_str(),
+                    "0",
+                    &row.name,
+                    &row.set_name,
+                    &row.collector_number,
+                    deckbox_condition(row.condition),
+                    language_name(&row.language),
+                    // Deckbox has no etched finish, they're listed as foils
+                    yes_or_empty(row.finish != Finish::NonFoil, "foil"),
+                    yes_or_empty(row.signed, "signed"),
+                    yes_or_empty(row.altered, "altered"),
+                ])?;
+            }
+        }
+        _ => {
+            writer.write_record([
+                "Quantity",
+                "Name",
+                "Set",
+                "Set Code",
+                "Card Number",
+                "Printing",
+                "Condition",
+                "Language",
+            ])?;
+            for row in rows {
+                writer.write_record([
+                    row.quantity.to_string().as_str(),
+                    &row.name,
+                    &row.set_name,
+                    &row.set.to_uppercase(),
+                    &row.collector_number,
+                    // TCGplayer lists etched foils as foils too
+                    if row.finish == Finish::NonFoil {
+                        "Normal"
+                    } else {
+                        "Foil"
+                    },
+                    condition_name(row.condition),
+                    language_name(&row.language),
+                ])?;
+            }
+        }
+    }
+    Ok(String::from_utf8(writer.into_inner()?)?)
+}
+
+/// Arena only cares about the printing, so every variant of one is a single line
+fn mtga(rows: &[ExportRow]) -> String {
+    let mut printings: Vec<(&ExportRow, usize)> = vec![];
+    for row in rows {
+        match printings
+            .iter_mut()
+            .find(|(printing, _)| printing.scryfall_id == row.scryfall_id)
+        {
+            Some((_, quantity)) => *quantity += row.quantity,
+            None => printings.push((row, row.quantity)),
+        }
+    }
+    printings
+        .iter()
+        .map(|(row, quantity)| {
+            format!(
+                "{} {} ({}) {}\n",
+                quantity,
+                row.name,
+                row.set.to_uppercase(),
+                row.collector_number
+            )
+        })
+        .collect()
+}
+
+/// Deckbox's own names, which don't line up with everyone else's: its "Played" is moderately played
+pub(crate) fn deckbox_condition(condition: Condition) -> &'static str {
+    match condition {
+        Condition::NearMint => "Near Mint",
+        Condition::LightlyPlayed => "Good (Lightly Played)",
+        Condition::ModeratelyPlayed => "Played",
+        Condition::HeavilyPlayed => "Heavily Played",
+        Condition::Damaged => "Poor",
+    }
+}
+
+pub(crate) fn condition_name(condition: Condition) -> &'static str {
+    match condition {
+        Condition::NearMint => "Near Mint",
+        Condition::LightlyPlayed => "Lightly Played",
+        Condition::ModeratelyPlayed => "Moderately Played",
+        Condition::HeavilyPlayed => "Heavily Played",
+        Condition::Damaged => "Damaged",
+    }
+}
+
+/// Most sites want language names rather than Scryfall's codes, unknown codes are passed through
+fn language_name(language: &str) -> &str {
+    match language {
+        "en" => "English",
+        "es" => "Spanish",
+        "fr" => "French",
+        "de" => "German",
+        "it" => "Italian",
+        "pt" => "Portuguese",
+        "ja" => "Japanese",
+        "ko" => "Korean",
+        "ru" => "Russian",
+        "zhs" => "Chinese Simplified",
+        "zht" => "Chinese Traditional",
+        "ph" => "Phyrexian",
+        other => other,
+    }
+}
+
+fn yes_or_empty(value: bool, yes: &'static str) -> &'static str {
+    if value {
+        yes
+    } else {
+        ""
+    }
+}
@@ -0,0 +1,122 @@
+use crate::config::load_config;
+
+use lazy_static::lazy_static;
+use serde::Deserialize;
+
+lazy_static! {
+    pub(crate) static ref FRAME_QUALITY: FrameQuality = load_config("./frame_quality.json");
+}
+
+/// How good a frame of a card has to be before it's worth running OCR on
+/// Set in `frame_quality.json`, for cameras that need looser (or stricter) limits
+#[derive(Deserialize)]
+#[serde(default)]
+pub(crate) struct FrameQuality {
+    /// Lowest variance of the card's Laplacian, blurry frames have few sharp edges
+    pub(crate) min_sharpness: f64,
+    /// Largest share of the card that can be blown out highlights, mostly glare off foils
+    pub(crate) max_glare: f64,
+    /// Largest average distance the corners can move between frames, as a share of the card's radius
+    pub(crate) max_motion: f64,
+    /// How many usable frames to pick the sharpest from
+    pub(crate) window: usize,
+}
+
+impl Default for FrameQuality {
+    fn default() -> Self {
+        Self {
+            min_sharpness: 60.0,
+            max_glare: 0.02,
+            max_motion: 0.03,
+            window: 3,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum Problem {
+    Moving,
+    Glare,
+    Blurry,
+}
+
+impl Problem {
+    /// What to tell the person holding the card
+    pub(crate) fn hint(&self) -> &'static str {
+        match self {
+            Problem::Moving => "Hold still",
+            Problem::Glare => "Glare, tilt the card away from the light",
+            Problem::Blurry => "Blurry, give the camera a moment to focus",
+        }
+    }
+}
+
+/// How one frame of a card measured up
+pub(crate) struct Measurement {
+    pub(crate) sharpness: f64,
+    pub(crate) glare: f64,
+    pub(crate) motion: f64,
+}
+
+impl Measurement {
+    /// The worst thing wrong with the frame, moving first since it causes blur too
+    pub(crate) fn problem(&self) -> Option<Problem> {
+        if self.motion > FRAME_QUALITY.max_motion {
+            Some(Problem::Moving)
+        } else if self.glare > FRAME_QUALITY.max_glare {
+            Some(Problem::Glare)
+        } else if self.sharpness < FRAME_QUALITY.min_sharpness {
+            Some(Problem::Blurry)
+        } else {
+            None
+        }
+    }
+}
+
+/// Average distance from each of the old corners to the nearest new one, as a share of `radius`
+/// Corners are matched by distance since the detected rectangle doesn't keep them in the same order
+pub(crate) fn corner_motion(old: &[[i32; 2]; 4], new: &[[i32; 2]; 4], radius: f64) -> f64 {
+    let moved: f64 = old
+        .iter()
+        .map(|[x1, y1]| {
+            new.iter()
+                .map(|[x2, y2]| ((x2 - x1) as f64).hypot((y2 - y1) as f64))
+                .fold(f64::MAX, f64::min)
+        })
+        .sum();
+    moved / 4.0 / radius.max(1.0)
+}
+
+/// The usable frames of a card seen lately, so OCR gets the sharpest of them rather than the first one
+pub(crate) struct FrameWindow<T> {
+    frames: Vec<(f64, T)>,
+}
+
+impl<T> Default for FrameWindow<T> {
+    fn default() -> Self {
+        Self { frames: vec![] }
+    }
+}
+
+impl<T> FrameWindow<T> {
+    /// Add a frame if it's usable, returning the sharpest one once the window is full
+    /// Movement starts the window over, since the earlier frames were of a card that wasn't settled yet
+    pub(crate) fn offer(&mut self, measurement: &Measurement, frame: T) -> Option<T> {
+        match measurement.problem() {
+            Some(Problem::Moving) => {
+                self.frames.clear();
+                return None;
+            }
+            Some(_) => return None,
+            None => (),
+        }
+        self.frames.push((measurement.sharpness, frame));
+        if self.frames.len() < FRAME_QUALITY.window {
+            return None;
+        }
+        self.frames
+            .drain(..)
+            .max_by(|(a, _), (b, _)| a.total_cmp(b))
+            .map(|(_, frame)| frame)
+    }
+}
@@ -1,4 +1,7 @@
 use crate::card;
+use crate::card_database::{CardDatabase, Session};
+use crate::card_store::{self, PrintingKey};
+use crate::frame_quality::Problem;
 use crate::search;
 use crate::text_extraction::extract_text_from_mat;
 
@@ -16,44 +19,84 @@ lazy_static::lazy_static! {
     pub(crate) static ref CARD: std::sync::Mutex<card::Card> = std::sync::Mutex::new(card::Card::default());
 }
 
-pub(crate) fn process_frame(frame_data: &[u8]) -> Result<Option<String>> {
+/// What a frame turned up
+#[derive(Default)]
+pub(crate) struct FrameResult {
+    /// Search results to show, when there are new ones
+    pub(crate) results: Option<String>,
+    /// A confidently recognised card that just left the frame, for the session to add, and the oracle ids to leave
+    /// out of that card's searches if it's undone
+    pub(crate) accepted: Option<(PrintingKey, std::collections::HashSet<String>)>,
+    /// Why the card in frame isn't being read yet
+    pub(crate) problem: Option<Problem>,
+}
+
+/// `database` is only locked while searching, since OCR is slow
+/// Only the sharpest of a few steady, glare free frames of a card gets read, see `frame_quality`
+/// With the session's `auto_accept` the card keeps being searched until its top candidate is stable, and leaving the frame
+/// (or another card taking its place) accepts it. Cards killed by a tap or a reject don't count as leaving
+pub(crate) fn process_frame(
+    frame_data: &[u8],
+    database: &std::sync::Mutex<CardDatabase>,
+    session: &Session,
+) -> Result<FrameResult> {
+    let auto_accept = session.auto_accept;
     let mut frame = imdecode(&Vector::from_slice(frame_data), IMREAD_COLOR)?;
 
-    let (alive, processed) = {
+    let mut result = FrameResult::default();
+    let (card_image, rejected, preprocessing) = {
         let mut card = CARD.lock().unwrap();
+        let mut left = None;
         if let Some(new_card) = get_card(&mut frame)? {
-            card.update(new_card);
+            left = card.update(new_card);
         }
+        let was_alive = card.alive;
         card.prune();
+        if was_alive && !card.alive {
+            left = card.tracker.take_accepted();
+        }
+        if auto_accept {
+            result.accepted = left.and_then(|(id, rejected)| {
+                Some((card_store::cards().faces(&id).first()?.clone(), rejected))
+            });
+        }
+
+        // Measured before the outline is drawn on the frame
+        let searching = !card.processed || (auto_accept && !card.tracker.is_stable());
+        let mut card_image = None;
+        if card.alive && searching {
+            let image = card.get_unwarped(&frame)?;
+            let measurement = card.measure(&image)?;
+            result.problem = measurement.problem();
+            card_image = card.window.offer(&measurement, image);
+        }
         card.draw(&mut frame);
-        (card.alive, card.processed)
+        (
+            card_image,
+            card.tracker.rejected().clone(),
+            card.tracker.preprocessing(),
+        )
     };
 
-    let mut results = None;
-    if alive && !processed {
+    if let Some(card_image) = card_image {
         // Extract tokens
-        if let Ok(text) = extract_text_from_mat(&frame) {
+        if let Ok(text) = extract_text_from_mat(&card_image, preprocessing) {
             // Filter to tokens in our dataset
             let text = search::filter_string(text);
             if !text.is_empty() {
-                // Get top 30 card matches
-                results = Some(search(&text));
+                // Get top 30 card matches, leaving out the ones rejected for this card
+                let database = database.lock().unwrap();
+                let ranked = search::rank(&text, &rejected, &session.pinned_sets, &database);
                 println!("Got search results for `{}`.", &text);
-                // TODO : Change search function to return IDs?
-                // TODO : Add another function which converts IDs to the final format
 
                 let mut card = CARD.lock().unwrap();
+                // Only send results again when they'd show something different
+                if card.tracker.observe(&ranked) || !card.processed {
+                    result.results = Some(search::results_json(&ranked, false, &database));
+                }
                 card.processed = true;
             }
         }
-
-        // TODO : Auto accept first result
-        // CONT :   I was hoping to just swipe it off frame
-        // CONT :     but that would interact poorly with the current rejection code / results speed
-        // CONT :   I was hoping to use the death signal as the accept signal
-        // CONT :     and to do that I'd need to add a different rejection signal/timer/handler
-        // CONT :   One idea I've had is to explicitly reject the IDs of the results
-        // CONT :     and filter future results instead of just making a new query
     }
 
     // Send the frame to the visualizer, if the visualizer is enabled
@@ -65,7 +108,7 @@ pub(crate) fn process_frame(frame_data: &[u8]) -> Result<Option<String>> {
             *global_frame = frame;
         }
     }
-    Ok(results)
+    Ok(result)
 }
 
 /// This function should take the raw camera image and normalize it for contour extraction
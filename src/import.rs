@@ -0,0 +1,623 @@
+use crate::card_database::{CardDatabase, Condition, Finish, Session, Variant};
+use crate::card_store::{self, CardStore, PrintingKey};
+
+use anyhow::{bail, Result};
+use std::{collections::HashMap, fs};
+
+/// The tools we know how to read exports from. They mostly differ in what they call their columns
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum ImportFormat {
+    Moxfield,
+    Archidekt,
+    Deckbox,
+    ManaBox,
+    Tcgplayer,
+    DelverLens,
+    /// `4 Lightning Bolt (M10) 146` lines, what MTGA, MTGO and most deckbuilders export
+    Text,
+}
+
+impl ImportFormat {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            ImportFormat::Moxfield => "moxfield",
+            ImportFormat::Archidekt => "archidekt",
+            ImportFormat::Deckbox => "deckbox",
+            ImportFormat::ManaBox => "manabox",
+            ImportFormat::Tcgplayer => "tcgplayer",
+            ImportFormat::DelverLens => "delver",
+            ImportFormat::Text => "text",
+        }
+    }
+
+    pub(crate) fn from_str(format: &str) -> Option<Self> {
+        match format.to_lowercase().as_str() {
+            "moxfield" => Some(ImportFormat::Moxfield),
+            "archidekt" => Some(ImportFormat::Archidekt),
+            "deckbox" => Some(ImportFormat::Deckbox),
+            "manabox" => Some(ImportFormat::ManaBox),
+            "tcgplayer" => Some(ImportFormat::Tcgplayer),
+            "delver" | "delverlens" => Some(ImportFormat::DelverLens),
+            "text" | "mtga" => Some(ImportFormat::Text),
+            _ => None,
+        }
+    }
+
+    /// Guess from the CSV header, anything with a name column we don't recognise is read like Delver Lens
+    /// since it lets you pick your own columns
+    fn detect(header: &[String]) -> Option<Self> {
+        let has = |column: &str| header.iter().any(|name| name == column);
+        if has("manabox id") {
+            Some(ImportFormat::ManaBox)
+        } else if has("edition code") && has("finish") {
+            Some(ImportFormat::Archidekt)
+        } else if has("tradelist count") && has("card number") {
+            Some(ImportFormat::Deckbox)
+        } else if has("tradelist count") {
+            Some(ImportFormat::Moxfield)
+        } else if has("product id") || has("sku") {
+            Some(ImportFormat::Tcgplayer)
+        } else if has("name") {
+            Some(ImportFormat::DelverLens)
+        } else {
+            None
+        }
+    }
+}
+
+/// What every row boils down to before we look it up
+#[derive(Default)]
+struct Entry {
+    quantity: usize,
+    name: String,
+    /// A set code or a set name, exports disagree on which they call "edition"
+    set: String,
+    collector_number: String,
+    scryfall_id: String,
+    variant: Variant,
+}
+
+/// A row we couldn't import, and why
+pub(crate) struct Unresolved {
+    pub(crate) line: usize,
+    pub(crate) text: String,
+    pub(crate) reason: String,
+}
+
+pub(crate) struct ImportReport {
+    pub(crate) format: ImportFormat,
+    pub(crate) rows: usize,
+    /// Copies of every resolved row, added up
+    pub(crate) cards: usize,
+    /// Rows without a set, we picked the newest printing for these
+    pub(crate) guessed: Vec<String>,
+    pub(crate) unresolved: Vec<Unresolved>,
+    /// The header of a CSV import, written out again above its unresolved rows
+    pub(crate) header: Option<String>,
+}
+
+impl ImportReport {
+    pub(crate) fn print(&self, dry_run: bool) {
+        println!(
+            "{} {} cards from {} {} rows.",
+            if dry_run { "Would import" } else { "Imported" },
+            self.cards,
+            self.rows,
+            self.format.as_str()
+        );
+        if !self.guessed.is_empty() {
+            println!(
+                "{} rows didn't say which printing, so the newest one was used:",
+                self.guessed.len()
+            );
+            for guessed in self.guessed.iter() {
+                println!("  {}", guessed);
+            }
+        }
+        if !self.unresolved.is_empty() {
+            println!(
+                "{} rows couldn't be matched to a card:",
+                self.unresolved.len()
+            );
+            for row in self.unresolved.iter() {
+                println!("  line {}: {} ({})", row.line, row.text, row.reason);
+            }
+        }
+    }
+
+    /// Where `write_unresolved` should go for an import of `path`, a CSV if that's what was imported
+    pub(crate) fn unresolved_path(&self, path: &str) -> String {
+        let extension = if self.header.is_some() { "csv" } else { "txt" };
+        format!("{}.unresolved.{}", path, extension)
+    }
+
+    /// The unresolved rows as they were in the file, header and all, so they can be fixed up and imported again
+    /// Rows the CSV reader couldn't read at all are left out, they're only in the printed report
+    pub(crate) fn write_unresolved(&self, path: &str) -> Result<()> {
+        let mut lines: Vec<&str> = self.header.iter().map(String::as_str).collect();
+        lines.extend(
+            self.unresolved
+                .iter()
+                .map(|row| row.text.as_str())
+                .filter(|text| !text.is_empty()),
+        );
+        fs::write(path, lines.join("\n") + "\n")?;
+        Ok(())
+    }
+}
+
+/// Read an export and add everything in it to the collection, or with `dry_run` just report what would happen
+pub(crate) fn import(
+    path: &str,
+    format: Option<ImportFormat>,
+    dry_run: bool,
+    session: &Session,
+    database: &mut CardDatabase,
+) -> Result<ImportReport> {
+    let contents = fs::read_to_string(path)?;
+    let format = match format {
+        Some(format) => format,
+        None if path.ends_with(".csv") => match csv_header(&contents)
+            .as_deref()
+            .and_then(ImportFormat::detect)
+        {
+            Some(format) => format,
+            None => bail!("Couldn't tell what exported {}, pass --format", path),
+        },
+        None => ImportFormat::Text,
+    };
+
+    let (header, entries) = if format == ImportFormat::Text {
+        (None, read_text(&contents))
+    } else {
+        let (header, entries) = read_csv(&contents)?;
+        (Some(header), entries)
+    };
+
+    let cards = card_store::cards();
+    let lookup = Lookup::new(&cards);
+    let mut report = ImportReport {
+        format,
+        rows: entries.len(),
+        cards: 0,
+        guessed: vec![],
+        unresolved: vec![],
+        header,
+    };
+    let mut copies = vec![];
+    for (line, text, entry) in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(reason) => {
+                report.unresolved.push(Unresolved { line, text, reason });
+                continue;
+            }
+        };
+        match lookup.resolve(&entry) {
+            Ok((id, guessed)) => {
+                if guessed {
+                    report.guessed.push(text);
+                }
+                report.cards += entry.quantity;
+                copies.push((PrintingKey::new(&id), entry.variant, entry.quantity));
+            }
+            Err(reason) => report.unresolved.push(Unresolved { line, text, reason }),
+        }
+    }
+
+    if !dry_run {
+        database.import(session, &copies)?;
+    }
+    Ok(report)
+}
+
+fn csv_header(contents: &str) -> Option<Vec<String>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(contents.as_bytes());
+    let header = reader.headers().ok()?;
+    Some(
+        header
+            .iter()
+            .map(|name| name.trim().to_lowercase())
+            .collect(),
+    )
+}
+
+/// Every row with its line number and original text, and either what's in it or why it couldn't be read
+type Rows = Vec<(usize, String, Result<Entry, String>)>;
+
+/// Header names for each field, across all of the formats. The first one a file has wins
+const QUANTITY: &[&str] = &["count", "quantity", "qty", "quantityx"];
+const NAME: &[&str] = &["name", "card name", "card"];
+const SET: &[&str] = &[
+    "set code",
+    "edition code",
+    "setcode",
+    "set",
+    "edition",
+    "set name",
+    "edition name",
+    "expansion",
+];
+const COLLECTOR_NUMBER: &[&str] = &[
+    "collector number",
+    "card number",
+    "collector's number",
+    "collector_number",
+    "number",
+    "cn",
+];
+const SCRYFALL_ID: &[&str] = &["scryfall id", "scryfall_id", "scryfallid"];
+const FINISH: &[&str] = &["finish", "foil", "printing"];
+const CONDITION: &[&str] = &["condition"];
+const LANGUAGE: &[&str] = &["language", "lang"];
+const SIGNED: &[&str] = &["signed"];
+const ALTERED: &[&str] = &["altered", "altered art", "alter"];
+
+/// The header as it was written and every row
+fn read_csv(contents: &str) -> Result<(String, Rows)> {
+    let mut reader = csv::ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(contents.as_bytes());
+    let header_line = csv_line(reader.headers()?)?;
+    let header: Vec<String> = reader
+        .headers()?
+        .iter()
+        .map(|name| name.trim().to_lowercase())
+        .collect();
+    let column = |names: &[&str]| {
+        names
+            .iter()
+            .find_map(|name| header.iter().position(|column| column == name))
+    };
+    let columns = [
+        column(QUANTITY),
+        column(NAME),
+        column(SET),
+        column(COLLECTOR_NUMBER),
+        column(SCRYFALL_ID),
+        column(FINISH),
+        column(CONDITION),
+        column(LANGUAGE),
+        column(SIGNED),
+        column(ALTERED),
+    ];
+
+    let mut rows = vec![];
+    for (index, record) in reader.records().enumerate() {
+        // The header is line 1
+        let line = index + 2;
+        let record = match record {
+            Ok(record) => record,
+            Err(e) => {
+                rows.push((line, String::new(), Err(e.to_string())));
+                continue;
+            }
+        };
+        let field = |index: usize| {
+            columns[index]
+                .and_then(|column| record.get(column))
+                .unwrap_or_default()
+                .trim()
+        };
+
+        let text = csv_line(&record)?;
+        let quantity = if columns[0].is_some() {
+            match field(0).parse() {
+                Ok(quantity) => quantity,
+                Err(_) => {
+                    rows.push((line, text, Err(format!("bad quantity `{}`", field(0)))));
+                    continue;
+                }
+            }
+        } else {
+            1
+        };
+        let mut variant = Variant::new(parse_finish(&format!("{} {}", field(5), field(6))));
+        if let Some(condition) = parse_condition(field(6)) {
+            variant.condition = condition;
+        }
+        if let Some(language) = parse_language(field(7)) {
+            variant.language = language;
+        }
+        variant.signed = is_set(field(8));
+        variant.altered = is_set(field(9));
+
+        rows.push((
+            line,
+            text,
+            Ok(Entry {
+                quantity,
+                name: field(1).to_owned(),
+                set: field(2).to_owned(),
+                collector_number: field(3).to_owned(),
+                scryfall_id: field(4).to_owned(),
+                variant,
+            }),
+        ));
+    }
+    Ok((header_line, rows))
+}
+
+/// A record as one line of CSV, quoted where it needs to be so names with commas survive
+fn csv_line(record: &csv::StringRecord) -> Result<String> {
+    let mut writer = csv::WriterBuilder::new().flexible(true).from_writer(vec![]);
+    writer.write_record(record)?;
+    let line = String::from_utf8(writer.into_inner()?)?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_owned())
+}
+
+/// The line number, text, quantity and name of every card in a decklist, for checking it against the collection
+/// rather than importing it
+pub(crate) fn read_decklist(contents: &str) -> Vec<(usize, String, usize, String)> {
+    read_text(contents)
+        .into_iter()
+        .filter_map(|(line, text, entry)| {
+            let entry = entry.ok()?;
+            Some((line, text, entry.quantity, entry.name))
+        })
+        .collect()
+}
+
+/// `4 Lightning Bolt (M10) 146`, `4x Lightning Bolt`, or just `Lightning Bolt`, with an optional `*F*` or `*E*`
+/// for foils and etched foils. Section headers like `Deck` and `Sideboard`, MTGO's `SB:` prefix, and `//` comments
+/// are skipped
+fn read_text(contents: &str) -> Rows {
+    let mut rows = vec![];
+    for (index, text) in contents.lines().enumerate() {
+        let mut line = text.trim();
+        if line.is_empty()
+            || line.starts_with("//")
+            || line.starts_with('#')
+            || ["deck", "sideboard", "commander", "companion", "maybeboard"]
+                .contains(&line.trim_end_matches(':').to_lowercase().as_str())
+        {
+            continue;
+        }
+        if let Some(stripped) = line.strip_prefix("SB:") {
+            line = stripped.trim_start();
+        }
+
+        let mut finish = Finish::NonFoil;
+        for (marker, marked_finish) in [("*F*", Finish::Foil), ("*E*", Finish::Etched)] {
+            if let Some(stripped) = line.strip_suffix(marker) {
+                line = stripped.trim_end();
+                finish = marked_finish;
+            }
+        }
+
+        let digits = line
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(line.len());
+        let quantity = if digits > 0 {
+            let quantity = line[..digits].parse().unwrap_or(1);
+            line = line[digits..].trim_start_matches(['x', 'X']).trim_start();
+            quantity
+        } else {
+            1
+        };
+
+        // `Name (SET) 123`, the collector number is optional
+        let (name, set, collector_number) = match (line.rfind('('), line.rfind(')')) {
+            (Some(open), Some(close)) if open < close => (
+                line[..open].trim(),
+                line[open + 1..close].trim(),
+                line[close + 1..].trim(),
+            ),
+            _ => (line, "", ""),
+        };
+
+        rows.push((
+            index + 1,
+            text.to_owned(),
+            Ok(Entry {
+                quantity,
+                name: name.to_owned(),
+                set: set.to_owned(),
+                collector_number: collector_number.to_owned(),
+                variant: Variant::new(finish),
+                ..Default::default()
+            }),
+        ));
+    }
+    rows
+}
+
+/// Indexes to find printings by what exports tend to have
+struct Lookup<'a> {
+    cards: &'a CardStore,
+    /// Set names to set codes
+    set_codes: HashMap<String, String>,
+    /// Lowercase names (and front face names of double faced cards) to printings, newest first
+    names: HashMap<String, Vec<String>>,
+}
+
+impl<'a> Lookup<'a> {
+    fn new(cards: &'a CardStore) -> Self {
+        let mut set_codes = HashMap::new();
+        let mut names: HashMap<String, Vec<String>> = HashMap::new();
+        for card in cards.cards() {
+            if let Some(set_name) = card.set_name() {
+                set_codes
+                    .entry(set_name.to_lowercase())
+                    .or_insert_with(|| card.set().to_owned());
+            }
+            let name = card.name().to_lowercase();
+            if let Some((front, _)) = name.split_once(" // ") {
+                names
+                    .entry(front.to_owned())
+                    .or_default()
+                    .push(card.id().to_owned());
+            }
+            names.entry(name).or_default().push(card.id().to_owned());
+        }
+        for ids in names.values_mut() {
+            ids.sort_by(|a, b| {
+                let released_at =
+                    |id: &str| cards.get_card_by_id(id).map(|card| card.released_at());
+                released_at(b).cmp(&released_at(a))
+            });
+        }
+        Self {
+            cards,
+            set_codes,
+            names,
+        }
+    }
+
+    /// The set code for a code or a set name
+    fn set_code(&self, set: &str) -> Option<String> {
+        let lowercase = set.to_lowercase();
+        if !self.cards.set_printings(&lowercase).is_empty() {
+            Some(lowercase)
+        } else {
+            self.set_codes.get(&lowercase).cloned()
+        }
+    }
+
+    /// Find the printing for an entry, and whether we had to guess which one it was
+    fn resolve(&self, entry: &Entry) -> Result<(String, bool), String> {
+        if !entry.scryfall_id.is_empty() && self.cards.get_card_by_id(&entry.scryfall_id).is_some()
+        {
+            return Ok((entry.scryfall_id.clone(), false));
+        }
+
+        let name = entry.name.to_lowercase();
+        if entry.set.is_empty() {
+            return match self.names.get(&name).and_then(|ids| ids.first()) {
+                Some(id) => Ok((id.clone(), true)),
+                None => Err(format!("no card named `{}`", entry.name)),
+            };
+        }
+
+        let Some(set) = self.set_code(&entry.set) else {
+            return Err(format!("unknown set `{}`", entry.set));
+        };
+        let printings = self.cards.set_printings(&set);
+        if !entry.collector_number.is_empty() {
+            if let Some(id) = printings.iter().find(|id| {
+                self.cards.get_card_by_id(id).is_some_and(|card| {
+                    card.collector_number()
+                        .eq_ignore_ascii_case(&entry.collector_number)
+                })
+            }) {
+                return Ok((id.clone(), false));
+            }
+        }
+        // Some exports don't have collector numbers, and some have them in a different format than Scryfall
+        let named: Vec<&String> = printings
+            .iter()
+            .filter(|id| {
+                self.cards.get_card_by_id(id).is_some_and(|card| {
+                    let card_name = card.name().to_lowercase();
+                    card_name == name
+                        || card_name
+                            .split_once(" // ")
+                            .is_some_and(|(front, _)| front == name)
+                })
+            })
+            .collect();
+        match named.as_slice() {
+            [id] => Ok(((*id).clone(), false)),
+            [id, ..] if entry.collector_number.is_empty() => Ok(((*id).clone(), true)),
+            [] => Err(format!("no `{}` in {}", entry.name, set.to_uppercase())),
+            _ => Err(format!(
+                "no {} #{} in {}",
+                entry.name,
+                entry.collector_number,
+                set.to_uppercase()
+            )),
+        }
+    }
+}
+
+/// Finishes are spelled a dozen ways (`foil`, `Foil`, `etched`, `Normal`, `Near Mint Foil`...)
+fn parse_finish(text: &str) -> Finish {
+    let text = text.to_lowercase();
+    if text.contains("etched") {
+        Finish::Etched
+    } else if text.contains("foil") && !text.contains("non") {
+        Finish::Foil
+    } else if ["true", "yes", "1"].contains(&text.trim()) {
+        // A `Foil` column with a boolean in it
+        Finish::Foil
+    } else {
+        Finish::NonFoil
+    }
+}
+
+fn parse_condition(text: &str) -> Option<Condition> {
+    let text = text
+        .to_lowercase()
+        .replace(['_', '-'], " ")
+        .replace("foil", "");
+    let text = text.trim();
+    Condition::from_str(text).or(match text {
+        "mint" | "m" | "near mint" => Some(Condition::NearMint),
+        // Deckbox's name, plain "good" is a grade lower everywhere else
+        "good (lightly played)" => Some(Condition::LightlyPlayed),
+        "lightly played" | "slightly played" | "sp" | "excellent" | "ex" => {
+            Some(Condition::LightlyPlayed)
+        }
+        "moderately played" | "played" | "pl" | "good" | "gd" => Some(Condition::ModeratelyPlayed),
+        "heavily played" => Some(Condition::HeavilyPlayed),
+        "damaged" | "poor" | "po" => Some(Condition::Damaged),
+        _ => None,
+    })
+}
+
+/// Scryfall language codes, from either a code or a language name
+fn parse_language(text: &str) -> Option<String> {
+    let language = match text.to_lowercase().trim() {
+        "" => return None,
+        "en" | "english" => "en",
+        "es" | "sp" | "spanish" => "es",
+        "fr" | "french" => "fr",
+        "de" | "german" => "de",
+        "it" | "italian" => "it",
+        "pt" | "portuguese" | "portuguese (brazil)" => "pt",
+        "ja" | "jp" | "japanese" => "ja",
+        "ko" | "kr" | "korean" => "ko",
+        "ru" | "russian" => "ru",
+        "zhs" | "cs" | "chinese simplified" | "simplified chinese" => "zhs",
+        "zht" | "ct" | "chinese traditional" | "traditional chinese" => "zht",
+        "ph" | "phyrexian" => "ph",
+        other => return Some(other.to_owned()),
+    };
+    Some(language.to_owned())
+}
+
+/// Checkbox columns hold anything from `true` to `signed` to `x`, empty means no
+fn is_set(text: &str) -> bool {
+    !text.is_empty() && !["false", "no", "0"].contains(&text.to_lowercase().as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::export;
+
+    const CONDITIONS: [Condition; 5] = [
+        Condition::NearMint,
+        Condition::LightlyPlayed,
+        Condition::ModeratelyPlayed,
+        Condition::HeavilyPlayed,
+        Condition::Damaged,
+    ];
+
+    #[test]
+    fn exported_conditions_import_unchanged() {
+        for condition in CONDITIONS {
+            assert_eq!(
+                parse_condition(export::deckbox_condition(condition)),
+                Some(condition)
+            );
+            assert_eq!(
+                parse_condition(export::condition_name(condition)),
+                Some(condition)
+            );
+            assert_eq!(parse_condition(condition.as_str()), Some(condition));
+        }
+    }
+}
@@ -1,12 +1,27 @@
+mod auto_accept;
 mod card;
 mod card_database;
+mod card_store;
+mod commands;
+mod config;
+mod decks;
+mod export;
+mod frame_quality;
 mod image;
 mod image_camera;
+mod import;
+mod prices;
+mod pricing;
+mod reports;
 mod search;
+mod sets;
 mod text_extraction;
+mod trades;
+mod update_data;
 mod websocket;
 use crate::search::search;
 
+use std::collections::HashMap;
 use warp::Filter;
 
 #[tokio::main]
@@ -26,18 +41,87 @@ async fn main() {
         }
     }
 
+    let args: Vec<String> = std::env::args().collect();
+    if commands::run(&args) {
+        return;
+    }
+
+    // `update-data <path or url>` pulls in a newer bulk file, the server is started separately afterwards
+    if args.get(1).map(String::as_str) == Some("update-data") {
+        match args.get(2) {
+            Some(source) => {
+                if let Err(e) = update_data::update_data(source).await {
+                    eprintln!("Failed to update data: {}", e);
+                    std::process::exit(1);
+                }
+            }
+            None => {
+                println!("Usage: gathering_the_magic update-data <default_cards.json path or url>");
+                std::process::exit(2);
+            }
+        }
+        return;
+    }
+
     let static_files = warp::get().and(warp::fs::file("./index.html"));
     let image_route = warp::path("images").and(warp::fs::dir("./images/"));
 
+    // Clients identify themselves with `?client=<id>` so history entries can say where they came from, and send back
+    // the `?session=<id>` they were given so a reload or reconnect carries on with the same session
     let websocket_route = warp::path("websocket")
         .and(warp::ws())
-        .map(|ws: warp::ws::Ws| ws.on_upgrade(websocket::handle_websocket));
+        .and(warp::query::<HashMap<String, String>>())
+        .map(|ws: warp::ws::Ws, query: HashMap<String, String>| {
+            let client_id = query
+                .get("client")
+                .cloned()
+                .unwrap_or_else(|| "unknown".to_owned());
+            let session_id = query.get("session").cloned();
+            ws.on_upgrade(move |websocket| {
+                websocket::handle_websocket(websocket, client_id, session_id)
+            })
+        });
 
-    let routes = websocket_route.or(image_route).or(static_files);
+    // `/export/<format>?collection=<name>` downloads the collection in another tool's format
+    let export_route = warp::path!("export" / String)
+        .and(warp::query::<HashMap<String, String>>())
+        .map(|format: String, query: HashMap<String, String>| {
+            let collection = query
+                .get("collection")
+                .cloned()
+                .unwrap_or_else(|| card_database::DEFAULT_COLLECTION.to_owned());
+            export_download(&format, &collection)
+        });
 
-    {
-        let _unused = search::ID_TO_FILES.lock().unwrap();
-    }
+    // `/report?collection=<name>&top=<n>&format=<json|csv|table>`, JSON unless another format is asked for
+    let report_route = warp::path!("report")
+        .and(warp::query::<HashMap<String, String>>())
+        .map(|query: HashMap<String, String>| report_download(&query));
+
+    // `/wantlist/<set>?collection=<name>&variants=true&showcase=true&tokens=true&sort=price`, a set's missing cards
+    let wantlist_route = warp::path!("wantlist" / String)
+        .and(warp::query::<HashMap<String, String>>())
+        .map(|set: String, query: HashMap<String, String>| wantlist_download(&set, &query));
+
+    // `/havelist?collection=<name>&keep=<n>`, the copies above `keep` of each card
+    let havelist_route = warp::path!("havelist")
+        .and(warp::query::<HashMap<String, String>>())
+        .map(|query: HashMap<String, String>| havelist_download(&query));
+
+    let routes = websocket_route
+        .or(export_route)
+        .or(report_route)
+        .or(wantlist_route)
+        .or(havelist_route)
+        .or(image_route)
+        .or(static_files);
+
+    // Load the card data before we start taking connections
+    let _ = card_store::cards();
+    card_database::collection(card_database::DEFAULT_COLLECTION)
+        .expect("Refusing to start without a valid collection database");
+    card_database::backup_collections();
+    prices::snapshot_current();
 
     println!("Starting web server...");
 
@@ -47,3 +131,144 @@ async fn main() {
 
     // image::run_visualizer().await.unwrap();
 }
+
+fn report_download(query: &HashMap<String, String>) -> warp::http::Response<String> {
+    let response = warp::http::Response::builder();
+    let collection = query
+        .get("collection")
+        .map_or(card_database::DEFAULT_COLLECTION, String::as_str);
+    let top = query
+        .get("top")
+        .and_then(|top| top.parse().ok())
+        .unwrap_or(reports::TOP_CARDS);
+    let format = query.get("format").map_or("json", String::as_str);
+    let Some(format) = reports::ReportFormat::from_str(format) else {
+        return response
+            .status(404)
+            .body("Unknown report format, use json, csv or table".to_owned())
+            .unwrap();
+    };
+    let contents = card_database::existing_collection(collection).and_then(|database| {
+        database
+            .map(|database| reports::report(&database.lock().unwrap(), top).write(format))
+            .transpose()
+    });
+    match contents {
+        Ok(None) => no_such_collection(collection),
+        Ok(Some(contents)) => response
+            .header("Content-Type", format.content_type())
+            .body(contents)
+            .unwrap(),
+        Err(e) => response
+            .status(400)
+            .body(format!("Failed to report on {}: {}", collection, e))
+            .unwrap(),
+    }
+}
+
+fn wantlist_download(set: &str, query: &HashMap<String, String>) -> warp::http::Response<String> {
+    let response = warp::http::Response::builder();
+    let collection = query
+        .get("collection")
+        .map_or(card_database::DEFAULT_COLLECTION, String::as_str);
+    let flag = |name: &str| query.get(name).is_some_and(|value| value == "true");
+    let options = sets::ChecklistOptions {
+        variants: flag("variants"),
+        showcase: flag("showcase"),
+        tokens: flag("tokens"),
+    };
+    let order = query
+        .get("sort")
+        .and_then(|sort| sets::ChecklistOrder::from_str(sort))
+        .unwrap_or(sets::ChecklistOrder::CollectorNumber);
+    let checklist = card_database::existing_collection(collection).map(|database| {
+        database.map(|database| sets::checklist(set, options, &database.lock().unwrap()))
+    });
+    match checklist {
+        Ok(None) => no_such_collection(collection),
+        Ok(Some(Some(checklist))) => response
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .header(
+                "Content-Disposition",
+                format!("attachment; filename=\"{}-wantlist.txt\"", checklist.set.to_lowercase()),
+            )
+            .body(checklist.wantlist(order))
+            .unwrap(),
+        Ok(Some(None)) => response
+            .status(404)
+            .body(format!("Unknown set {}", set))
+            .unwrap(),
+        Err(e) => response
+            .status(400)
+            .body(format!("Failed to open {}: {}", collection, e))
+            .unwrap(),
+    }
+}
+
+fn havelist_download(query: &HashMap<String, String>) -> warp::http::Response<String> {
+    let response = warp::http::Response::builder();
+    let collection = query
+        .get("collection")
+        .map_or(card_database::DEFAULT_COLLECTION, String::as_str);
+    let keep = query
+        .get("keep")
+        .and_then(|keep| trades::keep_threshold(keep))
+        .unwrap_or(trades::KEEP_COPIES);
+    match card_database::existing_collection(collection) {
+        Ok(None) => no_such_collection(collection),
+        Ok(Some(database)) => response
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .header(
+                "Content-Disposition",
+                format!("attachment; filename=\"{}-havelist.txt\"", collection),
+            )
+            .body(trades::have_list(&trades::tradeables(&database.lock().unwrap(), keep)))
+            .unwrap(),
+        Err(e) => response
+            .status(400)
+            .body(format!("Failed to open {}: {}", collection, e))
+            .unwrap(),
+    }
+}
+
+fn export_download(format: &str, collection: &str) -> warp::http::Response<String> {
+    let response = warp::http::Response::builder();
+    let Some(format) = export::ExportFormat::from_str(format) else {
+        return response
+            .status(404)
+            .body(format!("Unknown export format {}", format))
+            .unwrap();
+    };
+    let contents = card_database::existing_collection(collection).and_then(|database| {
+        database
+            .map(|database| export::export(format, &database.lock().unwrap()))
+            .transpose()
+    });
+    match contents {
+        Ok(None) => no_such_collection(collection),
+        Ok(Some(contents)) => response
+            .header("Content-Type", format.content_type())
+            .header(
+                "Content-Disposition",
+                format!(
+                    "attachment; filename=\"{}-{}.{}\"",
+                    collection,
+                    format.as_str(),
+                    format.extension()
+                ),
+            )
+            .body(contents)
+            .unwrap(),
+        Err(e) => response
+            .status(400)
+            .body(format!("Failed to export {}: {}", collection, e))
+            .unwrap(),
+    }
+}
+
+fn no_such_collection(collection: &str) -> warp::http::Response<String> {
+    warp::http::Response::builder()
+        .status(404)
+        .body(format!("There's no collection named {}", collection))
+        .unwrap()
+}
@@ -0,0 +1,359 @@
+use crate::card_database::{self, CardDatabase, Finish, HistoryFilter, Totals};
+use crate::card_store::{self, CardStore};
+use crate::pricing::{Price, Quotes, PRICING};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, NaiveDate, Utc};
+use rusqlite::{params, Connection};
+use scryers::{
+    bulk::{BulkDownload, BulkDownloadType},
+    card::Card,
+};
+use std::collections::{HashMap, HashSet};
+
+/// Prices are the same whichever collection a card is in, so they're kept on their own
+const PRICES_PATH: &str = "./prices.sqlite";
+
+const MIGRATIONS: &[&str] = &[
+    r#"
+    CREATE TABLE prices (
+        scryfall_id TEXT NOT NULL,
+        finish TEXT NOT NULL,
+        date TEXT NOT NULL,
+        usd REAL NOT NULL,
+        PRIMARY KEY (scryfall_id, finish, date)
+    );
+"#,
+    r#"
+    CREATE TABLE quotes (
+        scryfall_id TEXT NOT NULL,
+        finish TEXT NOT NULL,
+        date TEXT NOT NULL,
+        usd REAL,
+        eur REAL,
+        tix REAL,
+        PRIMARY KEY (scryfall_id, finish, date)
+    );
+    INSERT INTO quotes (scryfall_id, finish, date, usd) SELECT scryfall_id, finish, date, usd FROM prices;
+    DROP TABLE prices;
+    ALTER TABLE quotes RENAME TO prices;
+"#,
+];
+
+/// Every price we've seen for the printings we own, one snapshot per bulk data date
+pub(crate) struct PriceStore {
+    connection: Connection,
+}
+
+impl PriceStore {
+    pub(crate) fn open() -> Result<Self> {
+        let mut connection = Connection::open(PRICES_PATH)?;
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        card_database::migrate(&mut connection, MIGRATIONS)?;
+        Ok(Self { connection })
+    }
+
+    /// Record the prices of every owned printing in `cards` as of `date`, replacing any snapshot from that date
+    /// Finishes without a price aren't recorded, so they don't look like they dropped to nothing
+    fn record<'a>(
+        &mut self,
+        date: NaiveDate,
+        cards: impl Iterator<Item = &'a Card>,
+    ) -> rusqlite::Result<usize> {
+        let owned = owned_printings();
+        let date = date.to_string();
+        let transaction = self.connection.transaction()?;
+        let mut recorded = 0;
+        for card in cards.filter(|card| owned.contains(card.id())) {
+            for finish in [Finish::NonFoil, Finish::Foil, Finish::Etched] {
+                let quotes = Quotes::of(card, finish);
+                if !quotes.is_empty() {
+                    transaction.execute(
+                        "INSERT OR REPLACE INTO prices (scryfall_id, finish, date, usd, eur, tix) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                        params![card.id(), finish.as_str(), date, quotes.usd, quotes.eur, quotes.tix],
+                    )?;
+                    recorded += 1;
+                }
+            }
+        }
+        transaction.commit()?;
+        Ok(recorded)
+    }
+
+    /// The dates we have snapshots for, oldest first
+    pub(crate) fn dates(&self) -> Vec<NaiveDate> {
+        let mut statement = self
+            .connection
+            .prepare_cached("SELECT DISTINCT date FROM prices ORDER BY date")
+            .unwrap();
+        statement
+            .query_map([], |row| row.get::<_, String>(0))
+            .unwrap()
+            .filter_map(|row| row.ok()?.parse().ok())
+            .collect()
+    }
+
+    /// Everything in the store, for looking prices up by date
+    pub(crate) fn history(&self) -> PriceHistory {
+        let mut prices: HashMap<(String, Finish), Vec<(NaiveDate, Quotes)>> = HashMap::new();
+        let mut statement = self
+            .connection
+            .prepare_cached(
+                "SELECT scryfall_id, finish, date, usd, eur, tix FROM prices ORDER BY date",
+            )
+            .unwrap();
+        let rows = statement
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    Quotes {
+                        usd: row.get(3)?,
+                        eur: row.get(4)?,
+                        tix: row.get(5)?,
+                    },
+                ))
+            })
+            .unwrap()
+            .filter_map(|row| row.ok());
+        for (scryfall_id, finish, date, quotes) in rows {
+            if let (Some(finish), Ok(date)) = (Finish::from_str(&finish), date.parse()) {
+                prices
+                    .entry((scryfall_id, finish))
+                    .or_default()
+                    .push((date, quotes));
+            }
+        }
+        PriceHistory { prices }
+    }
+}
+
+/// Past prices of each printing and finish, oldest first
+pub(crate) struct PriceHistory {
+    prices: HashMap<(String, Finish), Vec<(NaiveDate, Quotes)>>,
+}
+
+impl PriceHistory {
+    /// The newest prices from on or before `date`, or the oldest ones we have if they're all later
+    fn quotes_on(&self, scryfall_id: &str, finish: Finish, date: NaiveDate) -> Quotes {
+        let Some(prices) = self.prices.get(&(scryfall_id.to_owned(), finish)) else {
+            return Quotes::default();
+        };
+        prices
+            .iter()
+            .rev()
+            .find(|(price_date, _)| *price_date <= date)
+            .or(prices.first())
+            .map(|(_, quotes)| *quotes)
+            .unwrap_or_default()
+    }
+
+    /// What a finish of a printing was worth on `date`, falling back the same way current prices do
+    pub(crate) fn price_on(
+        &self,
+        scryfall_id: &str,
+        finish: Finish,
+        date: NaiveDate,
+    ) -> Option<Price> {
+        PRICING.price(finish, |finish| self.quotes_on(scryfall_id, finish, date))
+    }
+}
+
+/// Snapshot the prices in the loaded bulk data, dated by when `scryfall.db` was last written
+pub(crate) fn snapshot_current() {
+    let result = file_date("./scryfall.db").and_then(|date| {
+        let cards = card_store::cards();
+        let recorded = PriceStore::open()?.record(date, cards.cards().iter())?;
+        Ok((date, recorded))
+    });
+    match result {
+        Ok((date, recorded)) => println!("Recorded {} prices for {}.", recorded, date),
+        Err(e) => eprintln!("Failed to record prices: {}", e),
+    }
+}
+
+/// Record the prices in an older bulk file, dated `date` or by when the file was written
+pub(crate) fn import_bulk(path: &str, date: Option<NaiveDate>) -> Result<(NaiveDate, usize)> {
+    let date = match date {
+        Some(date) => date,
+        None => file_date(path)?,
+    };
+    let bulk = BulkDownload::new(path, BulkDownloadType::DefaultCards)
+        .map_err(|e| anyhow!("Couldn't read bulk data from {}: {}", path, e))?;
+    let recorded = PriceStore::open()?.record(date, bulk.cards().iter())?;
+    Ok((date, recorded))
+}
+
+fn file_date(path: &str) -> Result<NaiveDate> {
+    let modified: DateTime<Utc> = std::fs::metadata(path)?.modified()?.into();
+    Ok(modified.date_naive())
+}
+
+/// Every printing any collection has held, even ones we've since gotten rid of, so their past value still counts
+fn owned_printings() -> HashSet<String> {
+    card_database::collection_names()
+        .iter()
+        .filter_map(|name| card_database::collection(name).ok())
+        .flat_map(|database| {
+            database
+                .lock()
+                .unwrap()
+                .holdings()
+                .into_iter()
+                .map(|holding| holding.key.scryfall_id)
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// The end of a day in UTC, the same cutoff `as-of` uses
+fn end_of_day(date: NaiveDate) -> DateTime<Utc> {
+    date.and_hms_opt(23, 59, 59).unwrap().and_utc()
+}
+
+/// The collection's totals at each snapshot, counting what it held on that day at that day's prices
+pub(crate) fn value_over_time(
+    database: &CardDatabase,
+    store: &PriceStore,
+) -> rusqlite::Result<Vec<(NaiveDate, Totals)>> {
+    let history = store.history();
+    let mut values = vec![];
+    for date in store.dates() {
+        let mut totals = Totals::default();
+        for ((key, variant), count) in database.replay(Some(end_of_day(date)))? {
+            totals.add(
+                count,
+                history.price_on(&key.scryfall_id, variant.finish, date),
+            );
+        }
+        values.push((date, totals));
+    }
+    Ok(values)
+}
+
+/// How the price of copies we own changed between two snapshots
+pub(crate) struct Mover {
+    pub(crate) name: String,
+    pub(crate) set: String,
+    pub(crate) finish: Finish,
+    pub(crate) count: usize,
+    pub(crate) before: Price,
+    pub(crate) after: Price,
+}
+
+impl Mover {
+    /// What the change is worth across every copy
+    pub(crate) fn change(&self) -> f64 {
+        (self.after.amount - self.before.amount) * self.count as f64
+    }
+}
+
+/// Every owned printing and finish priced at both dates, biggest gain first, and how many copies couldn't be
+/// priced on both
+pub(crate) fn movers(
+    database: &CardDatabase,
+    history: &PriceHistory,
+    from: NaiveDate,
+    to: NaiveDate,
+) -> (Vec<Mover>, usize) {
+    let cards = card_store::cards();
+    let mut counts: HashMap<(String, Finish), usize> = HashMap::new();
+    for holding in database.holdings() {
+        if holding.count > 0 {
+            *counts
+                .entry((holding.key.scryfall_id, holding.variant.finish))
+                .or_default() += holding.count;
+        }
+    }
+
+    let mut movers = vec![];
+    let mut unpriced = 0;
+    for ((scryfall_id, finish), count) in counts {
+        let card = cards.get_card_by_id(&scryfall_id);
+        let before = history.price_on(&scryfall_id, finish, from);
+        let after = history.price_on(&scryfall_id, finish, to);
+        match (card, before, after) {
+            (Some(card), Some(before), Some(after)) => movers.push(Mover {
+                name: card.name().to_owned(),
+                set: card.set().to_uppercase(),
+                finish,
+                count,
+                before,
+                after,
+            }),
+            _ => unpriced += count,
+        }
+    }
+    movers.sort_by(|a, b| b.change().total_cmp(&a.change()));
+    (movers, unpriced)
+}
+
+/// What one scanning session added to the collection
+pub(crate) struct SessionValue {
+    /// `None` for changes from before sessions were recorded
+    pub(crate) session_id: Option<String>,
+    pub(crate) client_id: Option<String>,
+    pub(crate) started_at: Option<i64>,
+    pub(crate) added: usize,
+    /// The added copies at the prices from when they were added
+    pub(crate) value: f64,
+    /// Added copies we couldn't find any price for
+    pub(crate) unpriced: usize,
+}
+
+/// Every session that added cards, oldest first
+pub(crate) fn session_values(
+    database: &CardDatabase,
+    history: &PriceHistory,
+    cards: &CardStore,
+) -> Vec<SessionValue> {
+    let mut sessions: Vec<SessionValue> = vec![];
+    // Oldest first, so each session starts at its first change
+    for entry in database
+        .filtered_history(&HistoryFilter::All, None)
+        .into_iter()
+        .rev()
+    {
+        let added = entry.updated_value.saturating_sub(entry.previous_value);
+        if added == 0 {
+            continue;
+        }
+        let date = entry
+            .created_at
+            .and_then(|created_at| DateTime::from_timestamp(created_at, 0))
+            .map_or(NaiveDate::MIN, |created_at| created_at.date_naive());
+        // Printings we never snapshotted fall back to what they're worth now
+        let price = history
+            .price_on(&entry.key.scryfall_id, entry.variant.finish, date)
+            .or_else(|| {
+                cards
+                    .get_card_by_id(&entry.key.scryfall_id)
+                    .and_then(|card| PRICING.card_price(card, entry.variant.finish))
+            });
+
+        let index = match sessions
+            .iter()
+            .position(|session| session.session_id == entry.session_id)
+        {
+            Some(index) => index,
+            None => {
+                sessions.push(SessionValue {
+                    session_id: entry.session_id.clone(),
+                    client_id: entry.client_id.clone(),
+                    started_at: entry.created_at,
+                    added: 0,
+                    value: 0.0,
+                    unpriced: 0,
+                });
+                sessions.len() - 1
+            }
+        };
+        sessions[index].added += added;
+        match price {
+            Some(price) => sessions[index].value += price.amount * added as f64,
+            None => sessions[index].unpriced += added,
+        }
+    }
+    sessions
+}
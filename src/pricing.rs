@@ -0,0 +1,222 @@
+use crate::card_database::Finish;
+use crate::config::load_config;
+
+use lazy_static::lazy_static;
+use scryers::card::Card;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+lazy_static! {
+    pub(crate) static ref PRICING: Pricing = load_config("./pricing.json");
+}
+
+/// The currencies Scryfall lists prices in
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Currency {
+    Usd,
+    Eur,
+    /// MTGO event tickets
+    Tix,
+}
+
+impl Currency {
+    const ALL: [Currency; 3] = [Currency::Usd, Currency::Eur, Currency::Tix];
+
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Currency::Usd => "usd",
+            Currency::Eur => "eur",
+            Currency::Tix => "tix",
+        }
+    }
+}
+
+/// The prices listed for one finish of a printing, `None` where there isn't one
+#[derive(Clone, Copy, Default, Debug)]
+pub(crate) struct Quotes {
+    pub(crate) usd: Option<f64>,
+    pub(crate) eur: Option<f64>,
+    pub(crate) tix: Option<f64>,
+}
+
+impl Quotes {
+    /// Scryfall has no etched euro price, and only one tix price which we count as the non-foil one
+    pub(crate) fn of(card: &Card, finish: Finish) -> Self {
+        let (usd, eur, tix) = match finish {
+            Finish::NonFoil => (card.usd(), card.eur(), card.tix()),
+            Finish::Foil => (card.usd_foil(), card.eur_foil(), 0.0),
+            Finish::Etched => (card.usd_etched(), 0.0, 0.0),
+        };
+        // Missing prices come through as 0
+        let listed = |price: f64| (price > 0.0).then_some(price);
+        Self {
+            usd: listed(usd),
+            eur: listed(eur),
+            tix: listed(tix),
+        }
+    }
+
+    pub(crate) fn get(&self, currency: Currency) -> Option<f64> {
+        match currency {
+            Currency::Usd => self.usd,
+            Currency::Eur => self.eur,
+            Currency::Tix => self.tix,
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.usd.is_none() && self.eur.is_none() && self.tix.is_none()
+    }
+}
+
+/// Which listed price a value came from
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) struct PriceSource {
+    pub(crate) currency: Currency,
+    pub(crate) finish: Finish,
+    /// The currency it was converted to, if it had to be
+    pub(crate) converted_to: Option<Currency>,
+}
+
+impl PriceSource {
+    /// Scryfall's name for the field, `usd_foil`, plus what it was converted to: `eur_foil→usd`
+    pub(crate) fn label(&self) -> String {
+        let field = match self.finish {
+            Finish::NonFoil => self.currency.as_str().to_owned(),
+            finish => format!("{}_{}", self.currency.as_str(), finish.as_str()),
+        };
+        match self.converted_to {
+            Some(currency) => format!("{}→{}", field, currency.as_str()),
+            None => field,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) struct Price {
+    /// In the preferred currency
+    pub(crate) amount: f64,
+    pub(crate) source: PriceSource,
+}
+
+/// Which currency values are shown in, and how to convert the others into it
+/// Set in `pricing.json`
+#[derive(Deserialize)]
+#[serde(default)]
+pub(crate) struct Pricing {
+    currency: Currency,
+    /// What one of each currency is worth in US dollars
+    rates: HashMap<Currency, f64>,
+}
+
+impl Default for Pricing {
+    fn default() -> Self {
+        Self {
+            currency: Currency::Usd,
+            rates: HashMap::from([(Currency::Usd, 1.0), (Currency::Eur, 1.08)]),
+        }
+    }
+}
+
+impl Pricing {
+    pub(crate) fn currency(&self) -> Currency {
+        self.currency
+    }
+
+    /// The price of a finish, from the preferred currency if it's listed and otherwise converted from another one.
+    /// If no currency lists that finish, the closest other finish is used
+    /// Tix are only used when they're the preferred currency, an MTGO price says nothing about what a paper copy is
+    /// worth, so a paper price for another finish is a better guess
+    pub(crate) fn price(&self, finish: Finish, quotes: impl Fn(Finish) -> Quotes) -> Option<Price> {
+        let currencies = std::iter::once(self.currency).chain(
+            Currency::ALL
+                .into_iter()
+                .filter(|currency| *currency != self.currency && *currency != Currency::Tix),
+        );
+        for finish in fallback_finishes(finish) {
+            let quotes = quotes(finish);
+            for currency in currencies.clone() {
+                let Some(listed) = quotes.get(currency) else {
+                    continue;
+                };
+                let Some(amount) = self.convert(listed, currency) else {
+                    continue;
+                };
+                return Some(Price {
+                    amount,
+                    source: PriceSource {
+                        currency,
+                        finish,
+                        converted_to: (currency != self.currency).then_some(self.currency),
+                    },
+                });
+            }
+        }
+        None
+    }
+
+    pub(crate) fn card_price(&self, card: &Card, finish: Finish) -> Option<Price> {
+        self.price(finish, |finish| Quotes::of(card, finish))
+    }
+
+    /// What the cheapest of a card's printings costs to buy. Like `price` it leaves tix out, so MTGO and Arena
+    /// printings don't count, and a printing priced in the preferred currency beats any that had to be converted,
+    /// which are only used when none of them are
+    pub(crate) fn cheapest<'a>(
+        &self,
+        printings: impl Iterator<Item = &'a Card>,
+        finish: Finish,
+    ) -> Option<Price> {
+        let prices: Vec<Price> = printings
+            .filter_map(|card| self.card_price(card, finish))
+            .collect();
+        let all_converted = prices
+            .iter()
+            .all(|price| price.source.converted_to.is_some());
+        prices
+            .into_iter()
+            .filter(|price| all_converted || price.source.converted_to.is_none())
+            .min_by(|a, b| a.amount.total_cmp(&b.amount))
+    }
+
+    /// `None` if there's no rate for either currency
+    fn convert(&self, amount: f64, from: Currency) -> Option<f64> {
+        if from == self.currency {
+            return Some(amount);
+        }
+        let from_rate = self.rates.get(&from)?;
+        let to_rate = self.rates.get(&self.currency).filter(|rate| **rate > 0.0)?;
+        Some(amount * from_rate / to_rate)
+    }
+
+    /// `$1.50`, `€1.50` or `1.50 tix`
+    pub(crate) fn format(&self, amount: f64) -> String {
+        match self.currency {
+            Currency::Usd => format!("${:.2}", amount),
+            Currency::Eur => format!("€{:.2}", amount),
+            Currency::Tix => format!("{:.2} tix", amount),
+        }
+    }
+
+    /// `"value": "$1.50", "price_source": "usd_foil"` for the UI, with an empty source when there's no price at all
+    pub(crate) fn json(&self, price: Option<Price>) -> String {
+        match price {
+            Some(price) => format!(
+                r#""value": "{}", "price_source": "{}""#,
+                self.format(price.amount),
+                price.source.label()
+            ),
+            None => r#""value": "no price", "price_source": """#.to_owned(),
+        }
+    }
+}
+
+/// The finish itself first, then the ones whose prices are usually closest to it
+fn fallback_finishes(finish: Finish) -> [Finish; 3] {
+    match finish {
+        Finish::NonFoil => [Finish::NonFoil, Finish::Foil, Finish::Etched],
+        Finish::Foil => [Finish::Foil, Finish::Etched, Finish::NonFoil],
+        Finish::Etched => [Finish::Etched, Finish::Foil, Finish::NonFoil],
+    }
+}
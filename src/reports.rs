@@ -0,0 +1,458 @@
+use crate::card_database::{CardDatabase, Finish, Totals};
+use crate::card_store::{self, CardStore};
+use crate::pricing::{Price, PRICING};
+use crate::sets::{self, ChecklistOptions, SetChecklist};
+
+use anyhow::Result;
+use scryers::card::Card;
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+
+/// How many of the most valuable cards a report lists unless told otherwise
+pub(crate) const TOP_CARDS: usize = 20;
+
+/// Formats legality is broken down by, the rest are too niche to be worth a row each
+const FORMATS: &[&str] = &[
+    "standard",
+    "pioneer",
+    "modern",
+    "legacy",
+    "vintage",
+    "commander",
+    "pauper",
+];
+
+const RARITIES: &[&str] = &["common", "uncommon", "rare", "mythic", "special", "bonus"];
+
+/// Cards with more than one of these (artifact creatures, say) count as the first one
+const TYPES: &[&str] = &[
+    "Creature",
+    "Planeswalker",
+    "Battle",
+    "Instant",
+    "Sorcery",
+    "Enchantment",
+    "Artifact",
+    "Land",
+];
+
+/// Upper bounds of the price bands, in `PRICING`'s currency
+const PRICE_BANDS: &[f64] = &[1.0, 5.0, 20.0, 100.0];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum ReportFormat {
+    Table,
+    Csv,
+    Json,
+}
+
+impl ReportFormat {
+    pub(crate) fn from_str(format: &str) -> Option<Self> {
+        match format.to_lowercase().as_str() {
+            "table" => Some(ReportFormat::Table),
+            "csv" => Some(ReportFormat::Csv),
+            "json" => Some(ReportFormat::Json),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn content_type(&self) -> &'static str {
+        match self {
+            ReportFormat::Table => "text/plain; charset=utf-8",
+            ReportFormat::Csv => "text/csv; charset=utf-8",
+            ReportFormat::Json => "application/json",
+        }
+    }
+}
+
+/// The copies in the collection split up by one property of their cards
+pub(crate) struct Breakdown {
+    pub(crate) title: &'static str,
+    pub(crate) groups: Vec<(String, Totals)>,
+}
+
+/// One of the most valuable printings and finishes we own
+pub(crate) struct TopCard {
+    pub(crate) name: String,
+    pub(crate) set: String,
+    pub(crate) collector_number: String,
+    pub(crate) finish: Finish,
+    pub(crate) count: usize,
+    /// Of one copy
+    pub(crate) price: Price,
+}
+
+pub(crate) struct Report {
+    pub(crate) totals: Totals,
+    /// By set, rarity, color identity, type, format legality and price band
+    pub(crate) breakdowns: Vec<Breakdown>,
+    /// Every set we own something from, most complete first
+    pub(crate) sets: Vec<SetChecklist>,
+    /// Most valuable per copy first
+    pub(crate) top: Vec<TopCard>,
+}
+
+/// Work out every breakdown of the collection, with the `top` most valuable cards
+pub(crate) fn report(database: &CardDatabase, top: usize) -> Report {
+    let cards = card_store::cards();
+    // Conditions and languages don't matter to any of this, and neither does which face was scanned
+    let mut copies: HashMap<(String, Finish), usize> = HashMap::new();
+    for holding in database.holdings() {
+        if holding.count > 0 {
+            *copies
+                .entry((holding.key.scryfall_id, holding.variant.finish))
+                .or_default() += holding.count;
+        }
+    }
+
+    let mut totals = Totals::default();
+    // One map per breakdown, in the order of `titles` below
+    let mut groups: [HashMap<String, Totals>; 6] = Default::default();
+    let mut top_cards = vec![];
+    for ((id, finish), count) in copies.iter() {
+        let card = cards.get_card_by_id(id);
+        let price = card.and_then(|card| PRICING.card_price(card, *finish));
+        totals.add(*count, price);
+        let Some(card) = card else {
+            continue;
+        };
+
+        let keys = [
+            vec![card.set().to_uppercase()],
+            vec![card.rarity().to_owned()],
+            vec![color_identity(card)],
+            vec![card_type(card)],
+            legal_formats(card),
+            vec![price_band(price)],
+        ];
+        for (groups, keys) in groups.iter_mut().zip(keys) {
+            for key in keys {
+                groups.entry(key).or_default().add(*count, price);
+            }
+        }
+        if let Some(price) = price {
+            top_cards.push(TopCard {
+                name: card.name().to_owned(),
+                set: card.set().to_uppercase(),
+                collector_number: card.collector_number().to_owned(),
+                finish: *finish,
+                count: *count,
+                price,
+            });
+        }
+    }
+    top_cards.sort_by(|a, b| b.price.amount.total_cmp(&a.price.amount));
+    top_cards.truncate(top);
+
+    let bands = band_labels();
+    let orders: [Option<Vec<&str>>; 6] = [
+        None,
+        Some(RARITIES.to_vec()),
+        None,
+        Some(TYPES.iter().copied().chain(["Other"]).collect()),
+        Some(FORMATS.to_vec()),
+        Some(bands.iter().map(String::as_str).collect()),
+    ];
+    let titles = [
+        "set",
+        "rarity",
+        "color identity",
+        "type",
+        "legal in",
+        "price",
+    ];
+    let breakdowns = groups
+        .into_iter()
+        .zip(orders)
+        .zip(titles)
+        .map(|((groups, order), title)| Breakdown {
+            title,
+            groups: sorted(groups, order),
+        })
+        .collect();
+
+    Report {
+        totals,
+        breakdowns,
+        sets: set_completion(&cards, copies.keys().map(|(id, _)| id.as_str()), database),
+        top: top_cards,
+    }
+}
+
+/// In `order` where there is one (anything not in it goes last), and otherwise biggest first
+fn sorted(groups: HashMap<String, Totals>, order: Option<Vec<&str>>) -> Vec<(String, Totals)> {
+    let mut groups: Vec<(String, Totals)> = groups.into_iter().collect();
+    match order {
+        Some(order) => groups.sort_by_key(|(label, _)| {
+            order
+                .iter()
+                .position(|ordered| ordered == label)
+                .unwrap_or(order.len())
+        }),
+        None => groups.sort_by(|(a_label, a), (b_label, b)| {
+            b.cards.cmp(&a.cards).then_with(|| a_label.cmp(b_label))
+        }),
+    }
+    groups
+}
+
+/// `WUG` in WUBRG order, or `Colorless`
+fn color_identity(card: &Card) -> String {
+    let colors: String = ["W", "U", "B", "R", "G"]
+        .into_iter()
+        .filter(|color| {
+            card.color_identity()
+                .iter()
+                .any(|identity| identity == color)
+        })
+        .collect();
+    if colors.is_empty() {
+        "Colorless".to_owned()
+    } else {
+        colors
+    }
+}
+
+/// The most important type on the front face
+fn card_type(card: &Card) -> String {
+    let type_line = card.type_line().as_deref().unwrap_or_default();
+    let front = type_line.split(" // ").next().unwrap_or_default();
+    let types = front.split('—').next().unwrap_or_default();
+    TYPES
+        .iter()
+        .find(|card_type| types.split_whitespace().any(|word| word == **card_type))
+        .map_or("Other".to_owned(), |card_type| (*card_type).to_owned())
+}
+
+fn legal_formats(card: &Card) -> Vec<String> {
+    FORMATS
+        .iter()
+        .filter(|format| {
+            card.legalities()
+                .get(**format)
+                .is_some_and(|legality| legality == "legal" || legality == "restricted")
+        })
+        .map(|format| (*format).to_owned())
+        .collect()
+}
+
+fn price_band(price: Option<Price>) -> String {
+    let labels = band_labels();
+    match price {
+        Some(price) => {
+            let band = PRICE_BANDS
+                .iter()
+                .position(|bound| price.amount < *bound)
+                .unwrap_or(PRICE_BANDS.len());
+            labels[band].clone()
+        }
+        None => labels[labels.len() - 1].clone(),
+    }
+}
+
+/// `under $1.00`, `$1.00 to $5.00`, ..., `$100.00 and up`, then `no price`
+fn band_labels() -> Vec<String> {
+    let mut labels = vec![format!("under {}", PRICING.format(PRICE_BANDS[0]))];
+    for bounds in PRICE_BANDS.windows(2) {
+        labels.push(format!(
+            "{} to {}",
+            PRICING.format(bounds[0]),
+            PRICING.format(bounds[1])
+        ));
+    }
+    labels.push(format!(
+        "{} and up",
+        PRICING.format(PRICE_BANDS[PRICE_BANDS.len() - 1])
+    ));
+    labels.push("no price".to_owned());
+    labels
+}
+
+/// Completion of every set with an owned printing, counted the same way as the set's own checklist
+fn set_completion<'a>(
+    cards: &CardStore,
+    owned: impl Iterator<Item = &'a str>,
+    database: &CardDatabase,
+) -> Vec<SetChecklist> {
+    let owned_sets: HashSet<&str> = owned
+        .filter_map(|id| cards.get_card_by_id(id))
+        .map(|card| card.set())
+        .collect();
+    let mut sets: Vec<SetChecklist> = owned_sets
+        .into_iter()
+        .filter_map(|set| sets::checklist(set, ChecklistOptions::default(), database))
+        // Token sets have nothing on them unless tokens are counted
+        .filter(|checklist| !checklist.cards.is_empty())
+        .collect();
+    sets.sort_by(|a, b| {
+        b.percent()
+            .total_cmp(&a.percent())
+            .then_with(|| a.set.cmp(&b.set))
+    });
+    sets
+}
+
+impl Report {
+    pub(crate) fn write(&self, format: ReportFormat) -> Result<String> {
+        match format {
+            ReportFormat::Table => Ok(self.table()),
+            ReportFormat::Csv => self.csv(),
+            ReportFormat::Json => Ok(serde_json::to_string_pretty(&self.json())?),
+        }
+    }
+
+    fn table(&self) -> String {
+        let mut table = format!(
+            "{} cards worth {}, {} unpriced\n",
+            self.totals.cards,
+            PRICING.format(self.totals.value),
+            self.totals.unpriced
+        );
+        for breakdown in self.breakdowns.iter() {
+            table += &format!(
+                "\n{:<24} {:>8} {:>14} {:>9}\n",
+                breakdown.title,
+                "cards",
+                format!("value ({})", PRICING.currency().as_str()),
+                "unpriced"
+            );
+            for (label, totals) in breakdown.groups.iter() {
+                table += &format!(
+                    "{:<24} {:>8} {:>14.2} {:>9}\n",
+                    label, totals.cards, totals.value, totals.unpriced
+                );
+            }
+        }
+
+        table += &format!(
+            "\n{:<8} {:<40} {:>7} {:>10} {:>8}\n",
+            "set", "name", "owned", "printings", "complete"
+        );
+        for set in self.sets.iter() {
+            table += &format!(
+                "{:<8} {:<40} {:>7} {:>10} {:>7.1}%\n",
+                set.set,
+                set.name,
+                set.owned(),
+                set.cards.len(),
+                set.percent()
+            );
+        }
+
+        table += &format!("\nThe {} most valuable cards:\n", self.top.len());
+        for card in self.top.iter() {
+            table += &format!(
+                "{:>4}  {:<40} {:<6} {:<6} {:<8} {:>10}  {}\n",
+                card.count,
+                card.name,
+                card.set,
+                card.collector_number,
+                card.finish.as_str(),
+                PRICING.format(card.price.amount),
+                card.price.source.label()
+            );
+        }
+        table
+    }
+
+    /// Every section in one file: `section,label,cards,value,unpriced,total`, where `total` is a set's printings
+    /// and for the most valuable cards `value` is the price of one copy
+    fn csv(&self) -> Result<String> {
+        let mut writer = csv::Writer::from_writer(vec![]);
+        writer.write_record(["section", "label", "cards", "value", "unpriced", "total"])?;
+        writer.write_record([
+            "total",
+            "",
+            self.totals.cards.to_string().as_str(),
+            &format!("{:.2}", self.totals.value),
+            &self.totals.unpriced.to_string(),
+            "",
+        ])?;
+        for breakdown in self.breakdowns.iter() {
+            for (label, totals) in breakdown.groups.iter() {
+                writer.write_record([
+                    breakdown.title,
+                    label,
+                    totals.cards.to_string().as_str(),
+                    &format!("{:.2}", totals.value),
+                    &totals.unpriced.to_string(),
+                    "",
+                ])?;
+            }
+        }
+        for set in self.sets.iter() {
+            writer.write_record([
+                "set completion",
+                &set.set,
+                set.owned().to_string().as_str(),
+                "",
+                "",
+                &set.cards.len().to_string(),
+            ])?;
+        }
+        for card in self.top.iter() {
+            writer.write_record([
+                "most valuable",
+                &format!(
+                    "{} ({}) {} {}",
+                    card.name,
+                    card.set,
+                    card.collector_number,
+                    card.finish.as_str()
+                ),
+                card.count.to_string().as_str(),
+                &format!("{:.2}", card.price.amount),
+                "",
+                "",
+            ])?;
+        }
+        Ok(String::from_utf8(writer.into_inner()?)?)
+    }
+
+    fn json(&self) -> serde_json::Value {
+        let totals_json = |totals: &Totals| {
+            json!({
+                "cards": totals.cards,
+                "value": totals.value,
+                "unpriced": totals.unpriced,
+            })
+        };
+        let breakdowns: serde_json::Map<String, serde_json::Value> = self
+            .breakdowns
+            .iter()
+            .map(|breakdown| {
+                let groups: Vec<serde_json::Value> = breakdown
+                    .groups
+                    .iter()
+                    .map(|(label, totals)| {
+                        let mut group = totals_json(totals);
+                        group["label"] = json!(label);
+                        group
+                    })
+                    .collect();
+                (breakdown.title.to_owned(), json!(groups))
+            })
+            .collect();
+        json!({
+            "currency": PRICING.currency().as_str(),
+            "totals": totals_json(&self.totals),
+            "breakdowns": breakdowns,
+            "sets": self.sets.iter().map(|set| json!({
+                "set": set.set,
+                "name": set.name,
+                "owned": set.owned(),
+                "printings": set.cards.len(),
+                "percent": set.percent(),
+            })).collect::<Vec<_>>(),
+            "top": self.top.iter().map(|card| json!({
+                "name": card.name,
+                "set": card.set,
+                "collector_number": card.collector_number,
+                "finish": card.finish.as_str(),
+                "count": card.count,
+                "price": card.price.amount,
+                "price_source": card.price.source.label(),
+            })).collect::<Vec<_>>(),
+        })
+    }
+}
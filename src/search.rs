@@ -1,118 +1,124 @@
+use crate::card_database::Finish;
+use crate::card_database::{CardDatabase, HistoryEntry};
+use crate::card_store::{self, oracle_key, CardStore, PrintingKey};
+use crate::config::load_config;
+use crate::pricing::PRICING;
+
 use lazy_static::lazy_static;
-use scryers::{
-    bulk::{BulkDownload, BulkDownloadType},
-    card::Card,
-};
+use scryers::card::Card;
+use serde::{Deserialize, Serialize};
 use std::{
     cmp::Ordering,
-    collections::{BinaryHeap, HashMap, HashSet},
-    sync::Mutex,
+    collections::{hash_map::Entry, BinaryHeap, HashMap, HashSet},
 };
 use strsim::jaro_winkler;
 
 lazy_static! {
-    pub(crate) static ref CARDS: Mutex<BulkDownload> =
-        Mutex::new(BulkDownload::new("./scryfall.db", BulkDownloadType::DefaultCards).unwrap());
-    pub(crate) static ref ID_TO_FILES: Mutex<HashMap<String, Vec<String>>> = {
-        let all_files: HashSet<String> = std::fs::read_dir(std::path::Path::new("./images/"))
-            .unwrap()
-            .map(|entry| {
-                entry
-                    .unwrap()
-                    .path()
-                    .file_name()
-                    .unwrap()
-                    .to_str()
-                    .unwrap()
-                    .to_owned()
-            })
-            .collect();
+    pub(crate) static ref SEARCH_WEIGHTS: FieldWeights = load_config("./search_weights.json");
+}
 
-        let cards = BulkDownload::new("./scryfall.db", BulkDownloadType::DefaultCards).unwrap();
-        Mutex::new(
-            cards
-                .cards()
-                .iter()
-                .map(|card| {
-                    (
-                        card.id().to_owned(),
-                        all_files
-                            .iter()
-                            .filter_map(|filename| {
-                                if filename.starts_with(card.id()) {
-                                    Some(filename.to_owned())
-                                } else {
-                                    None
-                                }
-                            })
-                            .collect(),
-                    )
-                })
-                .collect(),
-        )
-    };
-    pub(crate) static ref TOKENS: Mutex<HashSet<String>> = {
-        let cards = BulkDownload::new("./scryfall.db", BulkDownloadType::DefaultCards).unwrap();
-        let mut tokens = HashSet::new();
-        for card in cards.cards().iter() {
-            if card.lang() != "en" {
-                continue;
-            }
+/// Field names in the order `rank` scores them
+const FIELDS: [&str; 6] = [
+    "name",
+    "oracle_text",
+    "type_line",
+    "keywords",
+    "flavor_name",
+    "flavor_text",
+];
 
-            tokens.extend(
-                card.name()
-                    .to_lowercase()
-                    .split_whitespace()
-                    .map(String::from),
-            );
-            if let Some(text) = card.oracle_text() {
-                tokens.extend(text.to_lowercase().split_whitespace().map(String::from));
-            }
-            if let Some(type_line) = card.type_line() {
-                tokens.extend(
-                    type_line
-                        .to_lowercase()
-                        .split_whitespace()
-                        .map(String::from),
-                );
-            }
-            tokens.extend(card.keywords().iter().map(String::from));
-            if let Some(flavor_name) = card.flavor_name() {
-                tokens.extend(
-                    flavor_name
-                        .to_lowercase()
-                        .split_whitespace()
-                        .map(String::from),
-                );
-            }
-            if let Some(flavor_text) = card.flavor_text() {
-                tokens.extend(
-                    flavor_text
-                        .to_lowercase()
-                        .split_whitespace()
-                        .map(String::from),
-                );
-            }
+/// Per-field weights used to combine the Jaro Winkler scores into a single score
+/// Set in `search_weights.json`
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(default)]
+pub(crate) struct FieldWeights {
+    name: f64,
+    oracle_text: f64,
+    type_line: f64,
+    keywords: f64,
+    flavor_name: f64,
+    flavor_text: f64,
+}
 
-            if let Some(set_name) = card.set_name() {
-                tokens.extend(set_name.to_lowercase().split_whitespace().map(String::from));
-            }
+impl Default for FieldWeights {
+    fn default() -> Self {
+        Self {
+            name: 1.0,
+            oracle_text: 0.6,
+            type_line: 0.4,
+            keywords: 0.4,
+            flavor_name: 0.8,
+            flavor_text: 0.3,
         }
+    }
+}
+
+impl FieldWeights {
+    fn as_array(&self) -> [f64; 6] {
+        [
+            self.name,
+            self.oracle_text,
+            self.type_line,
+            self.keywords,
+            self.flavor_name,
+            self.flavor_text,
+        ]
+    }
+}
 
-        Mutex::new(tokens)
-    };
+/// A ranked card along with how it got its score
+#[derive(Clone)]
+pub(crate) struct SearchResult {
+    pub(crate) id: String,
+    pub(crate) oracle_id: String,
+    pub(crate) score: f64,
+    /// Raw score for each entry in `FIELDS`, `None` if the card doesn't have that field
+    pub(crate) field_scores: [Option<f64>; 6],
+    /// How much of `score` came from the card's set being in the scanning context
+    pub(crate) set_boost: f64,
+}
+
+impl SearchResult {
+    /// The field that contributed the most to the combined score
+    pub(crate) fn matched_field(&self) -> &'static str {
+        let weights = SEARCH_WEIGHTS.as_array();
+        FIELDS
+            .iter()
+            .zip(self.field_scores.iter().zip(weights.iter()))
+            .filter_map(|(field, (score, weight))| score.map(|score| (field, score * weight)))
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map_or(FIELDS[0], |(field, _)| field)
+    }
+
+    pub(crate) fn explain(&self) -> String {
+        let components = FIELDS
+            .iter()
+            .zip(self.field_scores.iter())
+            .map(|(field, score)| match score {
+                Some(score) => format!(r#""{}": "{:.3}""#, field, score),
+                None => format!(r#""{}": null"#, field),
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        format!(
+            r#"{{"field": "{}", "components": {{{}}}, "set_boost": "{:.3}"}}"#,
+            self.matched_field(),
+            components,
+            self.set_boost
+        )
+    }
 }
 
 struct ScoredCard<'a> {
     score: f64,
-    is_recent_set: bool,
+    field_scores: [Option<f64>; 6],
+    set_boost: f64,
     card: &'a Card,
 }
 
 impl<'a> PartialEq for ScoredCard<'a> {
     fn eq(&self, other: &Self) -> bool {
-        ((self.score - other.score).abs() < f64::EPSILON)
-            && (self.is_recent_set == other.is_recent_set)
+        (self.score - other.score).abs() < f64::EPSILON
     }
 }
 
@@ -127,77 +133,171 @@ impl<'a> Ord for ScoredCard<'a> {
 #[allow(clippy::non_canonical_partial_ord_impl)]
 impl<'a> PartialOrd for ScoredCard<'a> {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        match other.score.partial_cmp(&self.score) {
-            Some(Ordering::Equal) => Some(self.is_recent_set.cmp(&other.is_recent_set).reverse()),
-            other => other,
+        other.score.partial_cmp(&self.score)
+    }
+}
+
+/// How much a card from a set at full context weight gets added to its score
+const SET_BOOST: f64 = 0.05;
+/// How much each older history entry counts towards the inferred set context
+const SET_DECAY: f64 = 0.9;
+/// Inferred sets under this weight aren't shown as part of the context
+const SET_CONTEXT_THRESHOLD: f64 = 0.25;
+
+/// Weight in (0, 1] for every set in the current scanning context
+/// Sets are inferred from the recent history (newest first) with newer scans counting more, the session's pinned sets
+/// always get full weight
+fn set_weights(
+    cards: &CardStore,
+    history: &[HistoryEntry],
+    pinned_sets: &[String],
+) -> HashMap<String, f64> {
+    let mut weights: HashMap<String, f64> = HashMap::new();
+    let mut decay = 1.0;
+    for history_entry in history.iter() {
+        if let Some(card) = cards.get_card_by_id(&history_entry.key.scryfall_id) {
+            *weights.entry(card.set().to_owned()).or_default() += decay;
+        }
+        decay *= SET_DECAY;
+    }
+
+    let max_weight = weights.values().cloned().fold(0.0, f64::max);
+    for weight in weights.values_mut() {
+        *weight /= max_weight;
+    }
+
+    for set in pinned_sets.iter() {
+        weights.insert(set.clone(), 1.0);
+    }
+    weights
+}
+
+/// Pin sets (comma or space separated codes) to a session's scanning context
+pub(crate) fn pin_sets(pinned_sets: &mut Vec<String>, sets: &str) {
+    for set in sets
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|set| !set.is_empty())
+    {
+        let set = set.to_lowercase();
+        if !pinned_sets.contains(&set) {
+            pinned_sets.push(set);
         }
     }
 }
 
-fn rank(query: &str) -> Vec<String> {
-    let cards = CARDS.lock().unwrap();
-    let mut heap = BinaryHeap::new();
+pub(crate) fn unpin_set(pinned_sets: &mut Vec<String>, set: &str) {
+    pinned_sets.retain(|pinned| !pinned.eq_ignore_ascii_case(set));
+}
+
+/// A session's pinned sets and the ones inferred for its scanning context, for the UI
+pub(crate) fn set_context(pinned_sets: &[String], database: &CardDatabase) -> String {
+    let cards = card_store::cards();
+    let weights = set_weights(&cards, &database.recent_history(30), pinned_sets);
 
-    // Get recent sets
-    let recent_sets: HashSet<_> = crate::card_database::CARD_DATABASE
-        .lock()
-        .unwrap()
-        .history
+    let mut inferred: Vec<(&String, &f64)> = weights
         .iter()
-        .rev()
-        .take(30)
-        .map(|history_entry| {
-            cards
-                .get_card_by_id(
-                    &history_entry.file_name[..history_entry.file_name.rfind('-').unwrap()],
-                )
-                .unwrap()
-                .set_name()
-                .to_owned()
-        })
-        .take(3)
+        .filter(|(set, weight)| **weight >= SET_CONTEXT_THRESHOLD && !pinned_sets.contains(set))
         .collect();
+    inferred.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+
+    format!(
+        r#"{{"pinned": [{}], "inferred": [{}]}}"#,
+        pinned_sets
+            .iter()
+            .map(|set| format!(r#""{}""#, set))
+            .collect::<Vec<_>>()
+            .join(", "),
+        inferred
+            .iter()
+            .take(3)
+            .map(|(set, weight)| format!(r#"{{"set": "{}", "weight": "{:.2}"}}"#, set, weight))
+            .collect::<Vec<_>>()
+            .join(", ")
+    )
+}
+
+/// The best weighted score of any field the card has, scaled so a perfect match on the heaviest field is 1
+/// Fields the card doesn't have are left out rather than counted as misses, so a card with only a name isn't held
+/// back, and every Jaro Winkler score is well above 0 even for unrelated text, so adding up fields would let a wordy
+/// card outscore a better name match on noise alone
+fn combine_scores(field_scores: &[Option<f64>; 6], weights: &[f64; 6]) -> f64 {
+    let max_weight = weights.iter().copied().fold(0.0, f64::max);
+    if max_weight <= 0.0 {
+        return 0.0;
+    }
+    field_scores
+        .iter()
+        .zip(weights.iter())
+        .filter_map(|(score, weight)| score.map(|score| score * weight))
+        .fold(0.0, f64::max)
+        / max_weight
+}
 
-    for card in cards.cards() {
-        let name_score = jaro_winkler(&card.name().to_lowercase(), &query.to_lowercase());
+/// The best 30 cards for `query`, best first, leaving out any oracle ids in `excluded`
+pub(crate) fn rank(
+    query: &str,
+    excluded: &HashSet<String>,
+    pinned_sets: &[String],
+    database: &CardDatabase,
+) -> Vec<SearchResult> {
+    let cards = card_store::cards();
+    let weights = SEARCH_WEIGHTS.as_array();
+    let query = query.to_lowercase();
+    let mut heap = BinaryHeap::new();
+
+    let set_weights = set_weights(&cards, &database.recent_history(30), pinned_sets);
 
-        let scores = [
-            name_score,
+    // Printings of the same card score (almost) the same, so only keep the best one of each
+    let mut best_printings: HashMap<String, ScoredCard> = HashMap::new();
+    for card in cards
+        .cards()
+        .iter()
+        .filter(|card| !excluded.contains(&oracle_key(card)))
+    {
+        let field_scores = [
+            Some(jaro_winkler(&card.name().to_lowercase(), &query)),
             card.oracle_text()
                 .as_ref()
-                .map(|text| jaro_winkler(&text.to_lowercase(), &query.to_lowercase()))
-                .unwrap_or(0.0),
+                .map(|text| jaro_winkler(&text.to_lowercase(), &query)),
             card.type_line()
                 .as_ref()
-                .map(|type_line| jaro_winkler(&type_line.to_lowercase(), &query.to_lowercase()))
-                .unwrap_or(0.0),
-            card.keywords().iter().fold(0.0, |max, keyword| {
-                let score = jaro_winkler(&keyword.to_lowercase(), &query.to_lowercase());
-                if score > max {
-                    score
-                } else {
-                    max
-                }
-            }),
+                .map(|type_line| jaro_winkler(&type_line.to_lowercase(), &query)),
+            card.keywords()
+                .iter()
+                .map(|keyword| jaro_winkler(&keyword.to_lowercase(), &query))
+                .max_by(|a, b| a.total_cmp(b)),
             card.flavor_name()
                 .as_ref()
-                .map(|flavor_name| jaro_winkler(&flavor_name.to_lowercase(), &query.to_lowercase()))
-                .unwrap_or(0.0),
+                .map(|flavor_name| jaro_winkler(&flavor_name.to_lowercase(), &query)),
             card.flavor_text()
                 .as_ref()
-                .map(|flavor_text| jaro_winkler(&flavor_text.to_lowercase(), &query.to_lowercase()))
-                .unwrap_or(0.0),
+                .map(|flavor_text| jaro_winkler(&flavor_text.to_lowercase(), &query)),
         ];
-        let max_score = *scores
-            .iter()
-            .max_by(|a, b| a.partial_cmp(b).unwrap())
-            .unwrap();
 
-        heap.push(ScoredCard {
-            score: max_score,
-            is_recent_set: recent_sets.contains(card.set_name()),
+        let set_boost = set_weights
+            .get(card.set())
+            .map_or(0.0, |weight| weight * SET_BOOST);
+        let scored = ScoredCard {
+            score: combine_scores(&field_scores, &weights) + set_boost,
+            field_scores,
+            set_boost,
             card,
-        });
+        };
+        match best_printings.entry(oracle_key(card)) {
+            Entry::Occupied(mut best) => {
+                // Ordering is reversed for the min-heap below
+                if scored < *best.get() {
+                    best.insert(scored);
+                }
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(scored);
+            }
+        }
+    }
+
+    for (oracle_id, scored) in best_printings {
+        heap.push((scored, oracle_id));
 
         if heap.len() > 30 {
             heap.pop();
@@ -205,37 +305,133 @@ fn rank(query: &str) -> Vec<String> {
     }
     heap.into_sorted_vec()
         .into_iter()
-        .map(|scored| scored.card.id().to_owned())
+        .map(|(scored, oracle_id)| SearchResult {
+            id: scored.card.id().to_owned(),
+            oracle_id,
+            score: scored.score,
+            field_scores: scored.field_scores,
+            set_boost: scored.set_boost,
+        })
         .collect()
 }
 
-pub(crate) fn search(query: &str) -> String {
-    let ids = rank(query);
-    let cards = CARDS.lock().unwrap();
-    let database = crate::card_database::CARD_DATABASE.lock().unwrap();
-    ids.into_iter()
+/// How many printings of a group are sent at a time
+const PRINTINGS_PER_PAGE: usize = 6;
+
+/// Every face of a card we have an image for, newest printing first
+fn oracle_faces<'a>(oracle_id: &str, cards: &'a CardStore) -> Vec<(PrintingKey, &'a Card)> {
+    cards
+        .printings(oracle_id)
+        .iter()
         .flat_map(|id| {
-            ID_TO_FILES
-                .lock()
-                .unwrap()
-                .get(&id)
-                .unwrap()
-                .iter()
-                .map(|file_id| {
-                    let card = cards.get_card_by_id(&id).unwrap();
-                    (
-                        file_id.clone(),
-                        database.get(file_id),
-                        database.get_foil(file_id),
-                        card.usd(),
-                    )
-                })
-                .collect::<Vec<(String, usize, usize, f64)>>()
+            let card = cards.get_card_by_id(id).unwrap();
+            cards.faces(id).iter().map(move |key| (key.clone(), card))
         })
-        .map(|(uuid, non_foil_count, foil_count, value)| {
+        .collect()
+}
+
+fn printing_json(
+    key: &PrintingKey,
+    card: &Card,
+    cards: &CardStore,
+    database: &CardDatabase,
+) -> String {
+    format!(
+        r#"{{"uuid": "{}", {}, {}, "set": "{}", "released_at": "{}"}}"#,
+        cards.image(key).unwrap_or_default(),
+        database.counts_json(key, card),
+        PRICING.json(PRICING.card_price(card, Finish::NonFoil)),
+        card.set(),
+        card.released_at()
+    )
+}
+
+fn printings_page_locked(
+    faces: &[(PrintingKey, &Card)],
+    page: usize,
+    cards: &CardStore,
+    database: &CardDatabase,
+) -> (String, bool) {
+    let printings = faces
+        .iter()
+        .skip(page * PRINTINGS_PER_PAGE)
+        .take(PRINTINGS_PER_PAGE)
+        .map(|(key, card)| printing_json(key, card, cards, database))
+        .collect::<Vec<_>>()
+        .join(", ");
+    (printings, faces.len() > (page + 1) * PRINTINGS_PER_PAGE)
+}
+
+/// One page of a group's printings, and whether there are more pages after it
+pub(crate) fn printings_page(
+    oracle_id: &str,
+    page: usize,
+    database: &CardDatabase,
+) -> (String, bool) {
+    let cards = card_store::cards();
+    let faces = oracle_faces(oracle_id, &cards);
+    printings_page_locked(&faces, page, &cards, database)
+}
+
+pub(crate) fn search(
+    query: &str,
+    explain: bool,
+    pinned_sets: &[String],
+    database: &CardDatabase,
+) -> String {
+    results_json(
+        &rank(query, &HashSet::new(), pinned_sets, database),
+        explain,
+        database,
+    )
+}
+
+/// Results from `rank` as the UI's groups, each with its first page of printings
+pub(crate) fn results_json(
+    results: &[SearchResult],
+    explain: bool,
+    database: &CardDatabase,
+) -> String {
+    let cards = card_store::cards();
+    results
+        .iter()
+        .map(|result| {
+            let card = cards.get_card_by_id(&result.id).unwrap();
+            let faces = oracle_faces(&result.oracle_id, &cards);
+            let owned: usize = cards
+                .printings(&result.oracle_id)
+                .iter()
+                .map(|id| database.owned(id))
+                .sum();
+            let mut locations: Vec<String> = faces
+                .iter()
+                .flat_map(|(key, _)| database.locations(key))
+                .map(|location| location.describe())
+                .collect();
+            locations.sort();
+            locations.dedup();
+            let (printings, has_more) = printings_page_locked(&faces, 0, &cards, database);
+            let explanation = if explain {
+                format!(r#", "explain": {}"#, result.explain())
+            } else {
+                String::new()
+            };
             format!(
-                r#"{{"uuid": "{}", "non_foil_count": "{}", "foil_count": "{}", "value": "{:.2}"}}"#,
-                uuid, non_foil_count, foil_count, value
+                r#"{{"oracle_id": "{}", "name": {}, "uuid": "{}", "score": "{:.3}", "owned": "{}", "locations": {}, "printing_count": "{}", "has_more": {}, "printings": [{}]{}}}"#,
+                result.oracle_id,
+                serde_json::to_string(card.name()).unwrap(),
+                cards
+                    .faces(&result.id)
+                    .first()
+                    .and_then(|key| cards.image(key))
+                    .unwrap_or_default(),
+                result.score,
+                owned,
+                serde_json::to_string(&locations).unwrap(),
+                faces.len(),
+                has_more,
+                printings,
+                explanation
             )
         })
         .collect::<Vec<_>>()
@@ -243,10 +439,40 @@ pub(crate) fn search(query: &str) -> String {
 }
 
 pub(crate) fn filter_string(input: String) -> String {
+    let cards = card_store::cards();
     input
         .split_whitespace()
-        .filter(|&token| TOKENS.lock().unwrap().contains(&token.to_lowercase()))
+        .filter(|&token| cards.is_token(&token.to_lowercase()))
         .take(4) // TODO : I'm going to forget about this and it's going to be a problem
         .collect::<Vec<&str>>()
         .join(" ")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exact_name_beats_near_name_with_long_text() {
+        let weights = FieldWeights::default().as_array();
+        // A vanilla creature, nothing but a name and a type line
+        let exact = [Some(1.0), None, Some(0.45), None, None, None];
+        // A near miss on the name, on a card with rules, keywords and flavor text for the query to half match
+        let near = [
+            Some(0.93),
+            Some(0.62),
+            Some(0.5),
+            Some(0.55),
+            None,
+            Some(0.6),
+        ];
+        assert!(combine_scores(&exact, &weights) > combine_scores(&near, &weights));
+    }
+
+    #[test]
+    fn missing_fields_dont_lower_the_score() {
+        let weights = FieldWeights::default().as_array();
+        let name_only = [Some(0.9), None, None, None, None, None];
+        assert_eq!(combine_scores(&name_only, &weights), 0.9);
+    }
+}
@@ -0,0 +1,210 @@
+use crate::card_database::{CardDatabase, Finish};
+use crate::card_store::{self, CardStore, PrintingKey};
+use crate::pricing::{Price, PRICING};
+
+use scryers::card::Card;
+use std::collections::HashSet;
+
+/// Which printings in a set count towards completing it. By default that's one printing of each card
+#[derive(Clone, Copy, Default, Debug)]
+pub(crate) struct ChecklistOptions {
+    /// Alternate printings of cards already in the set: borderless, extended art and the like
+    pub(crate) variants: bool,
+    /// Printings in the set's showcase frame
+    pub(crate) showcase: bool,
+    /// The set's tokens, which Scryfall keeps in a set of their own
+    pub(crate) tokens: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum ChecklistOrder {
+    CollectorNumber,
+    /// Most expensive first, cards without a price last
+    Price,
+}
+
+impl ChecklistOrder {
+    pub(crate) fn from_str(order: &str) -> Option<Self> {
+        match order.to_lowercase().as_str() {
+            "number" | "collector_number" => Some(ChecklistOrder::CollectorNumber),
+            "price" => Some(ChecklistOrder::Price),
+            _ => None,
+        }
+    }
+}
+
+/// One printing on a set's checklist
+pub(crate) struct ChecklistCard {
+    pub(crate) key: PrintingKey,
+    pub(crate) name: String,
+    pub(crate) set: String,
+    pub(crate) collector_number: String,
+    pub(crate) owned: usize,
+    pub(crate) price: Option<Price>,
+}
+
+/// Every printing in a set that counts towards completing it, in collector number order
+pub(crate) struct SetChecklist {
+    pub(crate) set: String,
+    pub(crate) name: String,
+    pub(crate) cards: Vec<ChecklistCard>,
+}
+
+impl SetChecklist {
+    pub(crate) fn owned(&self) -> usize {
+        self.cards.iter().filter(|card| card.owned > 0).count()
+    }
+
+    pub(crate) fn percent(&self) -> f64 {
+        self.owned() as f64 * 100.0 / self.cards.len().max(1) as f64
+    }
+
+    pub(crate) fn missing(&self, order: ChecklistOrder) -> Vec<&ChecklistCard> {
+        let mut missing: Vec<&ChecklistCard> =
+            self.cards.iter().filter(|card| card.owned == 0).collect();
+        if order == ChecklistOrder::Price {
+            missing.sort_by(|a, b| match (a.price, b.price) {
+                (Some(a), Some(b)) => b.amount.total_cmp(&a.amount),
+                (a, b) => b.is_some().cmp(&a.is_some()),
+            });
+        }
+        missing
+    }
+
+    /// What it would cost to buy every missing card, and how many of them have no price
+    pub(crate) fn cost_to_complete(&self) -> (f64, usize) {
+        let missing = self.missing(ChecklistOrder::CollectorNumber);
+        let unpriced = missing.iter().filter(|card| card.price.is_none()).count();
+        let cost = missing
+            .iter()
+            .filter_map(|card| card.price)
+            .map(|price| price.amount)
+            .sum();
+        (cost, unpriced)
+    }
+
+    /// The missing cards as `1 Lightning Bolt (M10) 146` lines, which deckbuilders and stores import as wantlists
+    pub(crate) fn wantlist(&self, order: ChecklistOrder) -> String {
+        self.missing(order)
+            .iter()
+            .map(|card| {
+                format!(
+                    "1 {} ({}) {}\n",
+                    card.name,
+                    card.set.to_uppercase(),
+                    card.collector_number
+                )
+            })
+            .collect()
+    }
+
+    pub(crate) fn to_json(&self, order: ChecklistOrder) -> String {
+        let cards = card_store::cards();
+        let (cost, unpriced) = self.cost_to_complete();
+        format!(
+            r#"{{"set": "{}", "name": {}, "owned": "{}", "total": "{}", "percent": "{:.1}", "cost": "{}", "unpriced": "{}", "missing": [{}]}}"#,
+            self.set,
+            serde_json::to_string(&self.name).unwrap(),
+            self.owned(),
+            self.cards.len(),
+            self.percent(),
+            PRICING.format(cost),
+            unpriced,
+            self.missing(order)
+                .iter()
+                .map(|card| format!(
+                    r#"{{"uuid": "{}", "name": {}, "set": "{}", "collector_number": "{}", {}}}"#,
+                    cards.image(&card.key).unwrap_or_default(),
+                    serde_json::to_string(&card.name).unwrap(),
+                    card.set,
+                    card.collector_number,
+                    PRICING.json(card.price)
+                ))
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+    }
+}
+
+/// The checklist for a set from the local card data, `None` if we don't have a set with that code
+pub(crate) fn checklist(
+    set: &str,
+    options: ChecklistOptions,
+    database: &CardDatabase,
+) -> Option<SetChecklist> {
+    let set = set.to_lowercase();
+    let cards = card_store::cards();
+    let printings = cards.set_printings(&set);
+    let name = printings
+        .first()
+        .and_then(|id| cards.get_card_by_id(id))
+        .and_then(|card| card.set_name().clone())?;
+
+    let mut in_set = set_cards(&cards, &set);
+    if options.tokens {
+        in_set.extend(set_cards(&cards, &format!("t{}", set)));
+    } else {
+        in_set.retain(|card| !is_token(card));
+    }
+
+    // The first printing of each card is the one from the main set, any later ones are variants
+    let mut seen_names = HashSet::new();
+    let checklist = in_set
+        .into_iter()
+        .filter(|card| {
+            if is_showcase(card) {
+                return options.showcase;
+            }
+            seen_names.insert(card.name().to_owned()) || options.variants
+        })
+        .map(|card| ChecklistCard {
+            key: PrintingKey::new(card.id()),
+            name: card.name().to_owned(),
+            set: card.set().to_owned(),
+            collector_number: card.collector_number().to_owned(),
+            owned: database.owned(card.id()),
+            price: PRICING.card_price(card, Finish::NonFoil),
+        })
+        .collect();
+
+    Some(SetChecklist {
+        set: set.to_uppercase(),
+        name,
+        cards: checklist,
+    })
+}
+
+/// A set's printings in collector number order, `12` before `100` and `12a` after `12`
+fn set_cards<'a>(cards: &'a CardStore, set: &str) -> Vec<&'a Card> {
+    let mut set_cards: Vec<&Card> = cards
+        .set_printings(set)
+        .iter()
+        .filter_map(|id| cards.get_card_by_id(id))
+        .collect();
+    set_cards.sort_by_cached_key(|card| collector_number_key(card.collector_number()));
+    set_cards
+}
+
+fn collector_number_key(collector_number: &str) -> (u32, String) {
+    let digits: String = collector_number
+        .chars()
+        .skip_while(|c| !c.is_ascii_digit())
+        .take_while(char::is_ascii_digit)
+        .collect();
+    (
+        digits.parse().unwrap_or(u32::MAX),
+        collector_number.to_owned(),
+    )
+}
+
+fn is_token(card: &Card) -> bool {
+    card.type_line()
+        .as_deref()
+        .is_some_and(|type_line| type_line.contains("Token"))
+}
+
+fn is_showcase(card: &Card) -> bool {
+    card.frame_effects()
+        .as_ref()
+        .is_some_and(|effects| effects.iter().any(|effect| effect == "showcase"))
+}
@@ -1,51 +1,44 @@
-use crate::image_camera;
-
 use leptess::{leptonica, tesseract};
-use opencv::{core::Vector, imgcodecs, imgproc, prelude::*};
+use opencv::{
+    core::{self, Vector},
+    imgcodecs, imgproc,
+    prelude::*,
+};
 
 lazy_static::lazy_static! {
     pub(crate) static ref TESSERACT_API: std::sync::Mutex<tesseract::TessApi> = std::sync::Mutex::new(tesseract::TessApi::new(None, "eng").unwrap());
 }
 
-pub(crate) fn extract_text_from_mat(frame: &Mat) -> Result<String, Box<dyn std::error::Error>> {
-    let card_image = {
-        let card = image_camera::CARD.lock().unwrap();
-        if let Ok(card_image) = (*card).get_unwarped(frame) {
-            Some(card_image.clone())
-        } else {
-            None
+/// Ways of cleaning up the card before OCR. When every candidate for a card has been rejected it's read again
+/// with the next one, since the same image would most likely give the same wrong text
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub(crate) enum Preprocessing {
+    #[default]
+    Gray,
+    /// Blurred to drop noise, then binarized with Otsu's method
+    Otsu,
+    /// Binarized against each neighbourhood, which copes better with glare and uneven light
+    Adaptive,
+}
+
+impl Preprocessing {
+    pub(crate) fn next(self) -> Self {
+        match self {
+            Preprocessing::Gray => Preprocessing::Otsu,
+            Preprocessing::Otsu => Preprocessing::Adaptive,
+            Preprocessing::Adaptive => Preprocessing::Gray,
         }
     }
-    .unwrap();
-
-    // TODO : Normalize?
-    // // Convert the image to grayscale
-    // let mut gray = Mat::default();
-    // imgproc::cvt_color(mat, &mut gray, imgproc::COLOR_BGR2GRAY, 0)?;
-
-    // // Apply Gaussian Blur to reduce noise
-    // let mut smoothed = Mat::default();
-    // imgproc::gaussian_blur(
-    //     &gray,
-    //     &mut smoothed,
-    //     core::Size::new(3, 3),
-    //     0.0,
-    //     0.0,
-    //     core::BORDER_DEFAULT,
-    // )?;
-
-    // // Binarize the image using Otsu's method
-    // let mut binary = Mat::default();
-    // imgproc::threshold(
-    //     &smoothed,
-    //     &mut binary,
-    //     0.0,
-    //     255.0,
-    //     imgproc::THRESH_BINARY | imgproc::THRESH_OTSU,
-    // )?;
+}
 
+/// Read the text off `card_image`, the card as unwarped by `Card::get_unwarped`
+pub(crate) fn extract_text_from_mat(
+    card_image: &Mat,
+    preprocessing: Preprocessing,
+) -> Result<String, Box<dyn std::error::Error>> {
     let mut gray = Mat::default();
-    imgproc::cvt_color(&card_image, &mut gray, imgproc::COLOR_BGR2GRAY, 0)?;
+    imgproc::cvt_color(card_image, &mut gray, imgproc::COLOR_BGR2GRAY, 0)?;
+    let gray = preprocess(gray, preprocessing)?;
 
     // Convert Mat to a format that Leptess can use
     let mut buf = Vector::new();
@@ -57,3 +50,42 @@ pub(crate) fn extract_text_from_mat(frame: &Mat) -> Result<String, Box<dyn std::
     api.set_image(&pix);
     Ok(api.get_utf8_text()?)
 }
+
+fn preprocess(gray: Mat, preprocessing: Preprocessing) -> opencv::Result<Mat> {
+    match preprocessing {
+        Preprocessing::Gray => Ok(gray),
+        Preprocessing::Otsu => {
+            let mut smoothed = Mat::default();
+            imgproc::gaussian_blur(
+                &gray,
+                &mut smoothed,
+                core::Size::new(3, 3),
+                0.0,
+                0.0,
+                core::BORDER_DEFAULT,
+            )?;
+            let mut binary = Mat::default();
+            imgproc::threshold(
+                &smoothed,
+                &mut binary,
+                0.0,
+                255.0,
+                imgproc::THRESH_BINARY | imgproc::THRESH_OTSU,
+            )?;
+            Ok(binary)
+        }
+        Preprocessing::Adaptive => {
+            let mut binary = Mat::default();
+            imgproc::adaptive_threshold(
+                &gray,
+                &mut binary,
+                255.0,
+                imgproc::ADAPTIVE_THRESH_GAUSSIAN_C,
+                imgproc::THRESH_BINARY,
+                31,
+                10.0,
+            )?;
+            Ok(binary)
+        }
+    }
+}
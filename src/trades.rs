@@ -0,0 +1,226 @@
+use crate::card_database::{CardDatabase, Finish, Variant};
+use crate::card_store::{self, oracle_key, PrintingKey};
+use crate::decks;
+use crate::import::{self, Unresolved};
+use crate::pricing::{Price, PRICING};
+
+use anyhow::Result;
+use std::{collections::HashMap, fs};
+
+/// How many copies of each card to hold on to unless told otherwise, a playset
+pub(crate) const KEEP_COPIES: usize = 4;
+
+/// A number of copies, or `constructed` for a playset and `cube` or `singleton` for one
+pub(crate) fn keep_threshold(keep: &str) -> Option<usize> {
+    match keep.to_lowercase().as_str() {
+        "constructed" | "playset" => Some(KEEP_COPIES),
+        "cube" | "singleton" | "commander" => Some(1),
+        keep => keep.parse().ok(),
+    }
+}
+
+/// Copies of one variant of a printing we can trade away
+pub(crate) struct Tradeable {
+    pub(crate) key: PrintingKey,
+    pub(crate) variant: Variant,
+    pub(crate) set: String,
+    pub(crate) collector_number: String,
+    pub(crate) count: usize,
+    /// Of one copy
+    pub(crate) price: Option<Price>,
+}
+
+/// A card we have more copies of than we want to keep, across every printing
+pub(crate) struct TradeGroup {
+    pub(crate) oracle: String,
+    pub(crate) name: String,
+    pub(crate) owned: usize,
+    /// Most valuable first
+    pub(crate) tradeables: Vec<Tradeable>,
+}
+
+impl TradeGroup {
+    pub(crate) fn extras(&self) -> usize {
+        self.tradeables
+            .iter()
+            .map(|tradeable| tradeable.count)
+            .sum()
+    }
+
+    pub(crate) fn value(&self) -> f64 {
+        self.tradeables
+            .iter()
+            .filter_map(|tradeable| Some(tradeable.price?.amount * tradeable.count as f64))
+            .sum()
+    }
+}
+
+/// Every card we have more than `keep` copies of, with the extras taken from its most valuable copies since
+/// those are the ones worth the most in a trade. Most valuable groups first
+pub(crate) fn tradeables(database: &CardDatabase, keep: usize) -> Vec<TradeGroup> {
+    let cards = card_store::cards();
+    // How many copies we have of each card, and which of them could be traded, before deciding which are extras
+    let mut by_oracle: HashMap<String, (usize, Vec<Tradeable>)> = HashMap::new();
+    for holding in database.holdings() {
+        let Some(card) = cards.get_card_by_id(&holding.key.scryfall_id) else {
+            continue;
+        };
+        // Copies in a deck count towards the ones we keep, but aren't ours to trade
+        let in_decks: usize = decks::deck_locations(&holding.key, None, database)
+            .iter()
+            .filter(|location| location.variant == holding.variant)
+            .map(|location| location.count)
+            .sum();
+        let (owned, copies) = by_oracle.entry(oracle_key(card)).or_default();
+        *owned += holding.count;
+        let count = holding.count.saturating_sub(in_decks);
+        if count > 0 {
+            copies.push(Tradeable {
+                set: card.set().to_uppercase(),
+                collector_number: card.collector_number().to_owned(),
+                price: PRICING.card_price(card, holding.variant.finish),
+                key: holding.key,
+                variant: holding.variant,
+                count,
+            });
+        }
+    }
+
+    let mut groups: Vec<TradeGroup> = by_oracle
+        .into_iter()
+        .filter_map(|(oracle, (owned, mut copies))| {
+            let mut extras = owned.checked_sub(keep).filter(|extras| *extras > 0)?;
+            let amount = |copy: &Tradeable| copy.price.map_or(0.0, |price| price.amount);
+            copies.sort_by(|a, b| amount(b).total_cmp(&amount(a)));
+
+            let mut tradeables = vec![];
+            for mut copy in copies {
+                if extras == 0 {
+                    break;
+                }
+                copy.count = copy.count.min(extras);
+                extras -= copy.count;
+                tradeables.push(copy);
+            }
+            let name = cards
+                .get_card_by_id(&tradeables.first()?.key.scryfall_id)?
+                .name()
+                .to_owned();
+            Some(TradeGroup {
+                oracle,
+                name,
+                owned,
+                tradeables,
+            })
+        })
+        .collect();
+    groups.sort_by(|a, b| {
+        b.value()
+            .total_cmp(&a.value())
+            .then_with(|| a.name.cmp(&b.name))
+    });
+    groups
+}
+
+/// The tradeables as `2 Lightning Bolt (M10) 146 *F*` lines, the format `import` and most sites read
+pub(crate) fn have_list(groups: &[TradeGroup]) -> String {
+    groups
+        .iter()
+        .flat_map(|group| {
+            group.tradeables.iter().map(|tradeable| {
+                format!(
+                    "{} {} ({}) {}{}\n",
+                    tradeable.count,
+                    group.name,
+                    tradeable.set,
+                    tradeable.collector_number,
+                    match tradeable.variant.finish {
+                        Finish::NonFoil => "",
+                        Finish::Foil => " *F*",
+                        Finish::Etched => " *E*",
+                    }
+                )
+            })
+        })
+        .collect()
+}
+
+pub(crate) fn to_json(groups: &[TradeGroup]) -> String {
+    let cards = card_store::cards();
+    groups
+        .iter()
+        .map(|group| {
+            format!(
+                r#"{{"name": {}, "owned": "{}", "extras": "{}", "value": "{}", "tradeables": [{}]}}"#,
+                serde_json::to_string(&group.name).unwrap(),
+                group.owned,
+                group.extras(),
+                PRICING.format(group.value()),
+                group
+                    .tradeables
+                    .iter()
+                    .map(|tradeable| format!(
+                        r#"{{"uuid": "{}", "set": "{}", "collector_number": "{}", "count": "{}", {}, {}}}"#,
+                        cards.image(&tradeable.key).unwrap_or_default(),
+                        tradeable.set,
+                        tradeable.collector_number,
+                        tradeable.count,
+                        tradeable.variant.to_json(),
+                        PRICING.json(tradeable.price)
+                    ))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// A card on a partner's want list that we have extras of
+pub(crate) struct TradeMatch<'a> {
+    pub(crate) group: &'a TradeGroup,
+    pub(crate) wanted: usize,
+}
+
+impl TradeMatch<'_> {
+    /// How many copies we can actually give them
+    pub(crate) fn count(&self) -> usize {
+        self.wanted.min(self.group.extras())
+    }
+}
+
+/// Compare a partner's want list with our tradeables by card name, so any printing they want matches
+pub(crate) fn match_wants<'a>(
+    path: &str,
+    groups: &'a [TradeGroup],
+) -> Result<(Vec<TradeMatch<'a>>, Vec<Unresolved>)> {
+    let contents = fs::read_to_string(path)?;
+    let cards = card_store::cards();
+    let oracles = decks::oracle_names(&cards);
+    let mut matches: Vec<TradeMatch> = vec![];
+    let mut unresolved = vec![];
+    for (line, text, quantity, name) in import::read_decklist(&contents) {
+        let Some(oracle) = oracles.get(&name.to_lowercase()) else {
+            unresolved.push(Unresolved {
+                line,
+                text,
+                reason: format!("no card named `{}`", name),
+            });
+            continue;
+        };
+        let Some(group) = groups.iter().find(|group| group.oracle == *oracle) else {
+            continue;
+        };
+        match matches
+            .iter_mut()
+            .find(|found| found.group.oracle == group.oracle)
+        {
+            Some(found) => found.wanted += quantity,
+            None => matches.push(TradeMatch {
+                group,
+                wanted: quantity,
+            }),
+        }
+    }
+    Ok((matches, unresolved))
+}
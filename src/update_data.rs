@@ -0,0 +1,213 @@
+use crate::card_store::{self, CardStore};
+
+use anyhow::{anyhow, Result};
+use scryers::{
+    bulk::{BulkDownload, BulkDownloadType},
+    card::Card,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    time::Duration,
+};
+
+/// What changed between the bulk data we have and the one we're updating to
+#[derive(Default)]
+pub(crate) struct DataDiff {
+    pub(crate) added: Vec<String>,
+    pub(crate) changed: Vec<String>,
+    pub(crate) removed: Vec<String>,
+}
+
+impl DataDiff {
+    fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.changed.is_empty() && self.removed.is_empty()
+    }
+
+    fn new(old_cards: &CardStore, new_cards: &BulkDownload) -> Self {
+        let old_fingerprints: HashMap<&str, String> = old_cards
+            .cards()
+            .iter()
+            .map(|card| (card.id(), fingerprint(card)))
+            .collect();
+
+        let mut diff = Self::default();
+        for card in new_cards.cards() {
+            match old_fingerprints.get(card.id()) {
+                None => diff.added.push(card.id().to_owned()),
+                Some(old_fingerprint) if *old_fingerprint != fingerprint(card) => {
+                    diff.changed.push(card.id().to_owned())
+                }
+                Some(_) => (),
+            }
+        }
+        let new_ids: HashSet<&str> = new_cards.cards().iter().map(|card| card.id()).collect();
+        diff.removed = old_cards
+            .cards()
+            .iter()
+            .filter(|card| !new_ids.contains(card.id()))
+            .map(|card| card.id().to_owned())
+            .collect();
+        diff
+    }
+
+    fn print_report(&self, old_cards: &CardStore, new_cards: &BulkDownload) {
+        println!(
+            "{} added, {} changed, {} removed printings",
+            self.added.len(),
+            self.changed.len(),
+            self.removed.len()
+        );
+        let print_card = |label: &str, card: &Card| {
+            println!(
+                "  {} {} ({} {}) {}",
+                label,
+                card.name(),
+                card.set().to_uppercase(),
+                card.collector_number(),
+                card.id()
+            )
+        };
+        for id in self.added.iter() {
+            print_card("+", new_cards.get_card_by_id(id).unwrap());
+        }
+        for id in self.changed.iter() {
+            print_card("~", new_cards.get_card_by_id(id).unwrap());
+        }
+        for id in self.removed.iter() {
+            print_card("-", old_cards.get_card_by_id(id).unwrap());
+        }
+    }
+}
+
+/// Only the fields we actually use, prices change every day and aren't worth reporting
+fn fingerprint(card: &Card) -> String {
+    format!(
+        "{}|{:?}|{:?}|{}|{}|{}|{:?}",
+        card.name(),
+        card.oracle_text(),
+        card.type_line(),
+        card.set(),
+        card.collector_number(),
+        card.released_at(),
+        card.oracle_id()
+    )
+}
+
+/// Where bulk data downloaded from a URL is kept until it's been read
+const DOWNLOAD_PATH: &str = "./scryfall.db.new";
+
+/// Update our card data from a newer `default_cards` bulk file (a local path or a URL to a mirror)
+/// Only the images we don't have yet are downloaded
+pub(crate) async fn update_data(source: &str) -> Result<()> {
+    let result = update_from(source).await;
+    // A download is copied into place once it's been read, and useless if it couldn't be
+    if is_url(source) {
+        let _ = fs::remove_file(DOWNLOAD_PATH);
+    }
+    result
+}
+
+fn is_url(source: &str) -> bool {
+    source.starts_with("http://") || source.starts_with("https://")
+}
+
+async fn update_from(source: &str) -> Result<()> {
+    let bulk_path = if is_url(source) {
+        println!("Downloading bulk data from {}...", source);
+        let bytes = reqwest::get(source)
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        fs::write(DOWNLOAD_PATH, &bytes)?;
+        DOWNLOAD_PATH.to_owned()
+    } else {
+        source.to_owned()
+    };
+
+    let new_cards = BulkDownload::new(&bulk_path, BulkDownloadType::DefaultCards)
+        .map_err(|e| anyhow!("Couldn't read bulk data from {}: {}", source, e))?;
+    let old_cards = card_store::cards();
+    let diff = DataDiff::new(&old_cards, &new_cards);
+    diff.print_report(&old_cards, &new_cards);
+
+    let missing_images: Vec<String> = new_cards
+        .cards()
+        .iter()
+        .filter(|card| old_cards.faces(card.id()).is_empty())
+        .map(|card| card.id().to_owned())
+        .collect();
+    download_images(&missing_images).await;
+
+    if bulk_path != "./scryfall.db" {
+        if fs::metadata("./scryfall.db").is_ok() {
+            fs::copy("./scryfall.db", "./scryfall.db.bak")?;
+        }
+        fs::copy(&bulk_path, "./scryfall.db")?;
+    }
+
+    card_store::replace(old_cards.updated(new_cards, &diff, &missing_images));
+    if !diff.is_empty() {
+        invalidate_hashes()?;
+    }
+    println!("Update complete.");
+    Ok(())
+}
+
+/// Where the perceptual hash databases are kept, one per hash size (see `image_hash`)
+const HASH_DIRECTORY: &str = "./hashes";
+
+/// The hash databases can only be computed with OpenCV from the images, which updating doesn't do, so rather than
+/// leave them missing the new printings and still matching removed ones, they're deleted and `hash_all_cards`
+/// recomputes them from the images the next time it runs
+fn invalidate_hashes() -> Result<()> {
+    let Ok(entries) = fs::read_dir(HASH_DIRECTORY) else {
+        return Ok(());
+    };
+    for entry in entries {
+        let path = entry?.path();
+        if path
+            .extension()
+            .is_some_and(|extension| extension == "json")
+        {
+            println!("Removing out of date hash database {}", path.display());
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+async fn download_images(ids: &[String]) {
+    println!("Downloading {} missing images...", ids.len());
+    for id in ids {
+        // Back faces don't exist for most cards, Scryfall just errors for those
+        for (face, suffix) in [("front", 0), ("back", 1)] {
+            let url = format!(
+                "https://api.scryfall.com/cards/{}?format=image&version=large&face={}",
+                id, face
+            );
+            match fetch_image(&url).await {
+                Ok(bytes) => {
+                    if let Err(e) = fs::write(format!("./images/{}-{}.jpg", id, suffix), bytes) {
+                        eprintln!("Failed to save image for {}: {}", id, e);
+                    }
+                }
+                Err(e) if face == "front" => eprintln!("Failed to download {}: {}", id, e),
+                Err(_) => (),
+            }
+
+            // Stay well under Scryfall's rate limits
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+}
+
+async fn fetch_image(url: &str) -> Result<Vec<u8>> {
+    Ok(reqwest::get(url)
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?
+        .to_vec())
+}
@@ -1,21 +1,134 @@
-use crate::search::search;
-use crate::{card_database, image_camera};
+use crate::auto_accept::AUTO_ACCEPT;
+use crate::card_database::{
+    Accepted, CardDatabase, Condition, Container, ContainerKind, Finish, Position, Session, Target,
+    Totals, Variant,
+};
+use crate::card_store::{self, PrintingKey};
+use crate::pricing::PRICING;
+use crate::search::{self, search};
+use crate::sets::{self, ChecklistOptions, ChecklistOrder};
+use crate::{card_database, image_camera, trades};
 
+use anyhow::{bail, Result};
 use futures::{stream::StreamExt, SinkExt};
 use serde::Deserialize;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 use warp::ws::{Message, WebSocket};
 
+lazy_static::lazy_static! {
+    /// Sessions whose connection closed, kept so a reload or reconnect carries on with the same one
+    static ref SUSPENDED_SESSIONS: Mutex<HashMap<String, (Session, Instant)>> = Mutex::new(HashMap::new());
+    /// Sessions with a connection right now, a session only ever belongs to one at a time
+    /// Always locked before `SUSPENDED_SESSIONS` when both are needed
+    static ref ATTACHED_SESSIONS: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+}
+
+/// How long a suspended session can be picked back up
+const SESSION_KEPT: Duration = Duration::from_secs(12 * 60 * 60);
+
 #[derive(Deserialize)]
 struct ActionMessage {
     action: String,
     message: Option<String>,
     count: Option<usize>,
+    explain: Option<bool>,
+    /// Which copy a count change applies to, anything missing falls back to `Variant::new`
+    finish: Option<String>,
+    condition: Option<String>,
+    language: Option<String>,
+    signed: Option<bool>,
+    altered: Option<bool>,
+    /// Collection to use instead of the session's, and the one to transfer to
+    collection: Option<String>,
+    to: Option<String>,
+    /// Container to put copies into, `from` is the one they're moved out of
+    container: Option<String>,
+    from: Option<String>,
+    kind: Option<String>,
+    /// Binder page or box row, and binder slot
+    section: Option<usize>,
+    slot: Option<usize>,
+    /// Which printings count towards completing a set, and how to sort what's missing
+    variants: Option<bool>,
+    showcase: Option<bool>,
+    tokens: Option<bool>,
+    sort: Option<String>,
+}
+
+impl ActionMessage {
+    /// The variant a count change is for, `default_finish` comes from the action (`incFoil` vs `incCard`)
+    fn variant(&self, default_finish: Finish) -> Variant {
+        let mut variant = Variant::new(
+            self.finish
+                .as_deref()
+                .and_then(Finish::from_str)
+                .unwrap_or(default_finish),
+        );
+        if let Some(condition) = self.condition.as_deref().and_then(Condition::from_str) {
+            variant.condition = condition;
+        }
+        if let Some(language) = &self.language {
+            variant.language = language.to_lowercase();
+        }
+        variant.signed = self.signed.unwrap_or_default();
+        variant.altered = self.altered.unwrap_or_default();
+        variant
+    }
+
+    /// The printing a card action is for, the UI names them by their image file
+    fn key(&self) -> Option<PrintingKey> {
+        let message = self.message.as_deref()?;
+        let key = card_store::cards().image_key(message).cloned();
+        if key.is_none() {
+            eprintln!("No printing has the image {}", message);
+        }
+        key
+    }
+
+    fn checklist_options(&self) -> ChecklistOptions {
+        ChecklistOptions {
+            variants: self.variants.unwrap_or_default(),
+            showcase: self.showcase.unwrap_or_default(),
+            tokens: self.tokens.unwrap_or_default(),
+        }
+    }
+
+    fn position(&self) -> Position {
+        Position {
+            section: self.section.unwrap_or_default(),
+            slot: self.slot.unwrap_or_default(),
+        }
+    }
 }
 
-pub(crate) async fn handle_websocket(websocket: WebSocket) {
+/// `session_id` is the session the page had before it reloaded or lost its connection, if any
+pub(crate) async fn handle_websocket(
+    websocket: WebSocket,
+    client_id: String,
+    session_id: Option<String>,
+) {
+    let mut session = resume_session(session_id.as_deref(), &client_id);
+    println!("Session {} started by {}", session.id, session.client_id);
     kill_card();
     let (mut tx, mut rx) = websocket.split();
-    update_recent(&mut tx).await;
+    let reply = Message::text(format!(
+        r#"{{"action": "session", "id": "{}"}}"#,
+        session.id
+    ));
+    assert!(tx.send(reply).await.is_ok());
+    if let Some(database) = open_collection(&session.collection) {
+        update_recent(&database, &mut tx).await;
+        send_set_context(&database, &session, &mut tx).await;
+    }
+    send_containers(&session, &mut tx).await;
+    send_collections(&session, &mut tx).await;
+    send_auto_accept(&session, &mut tx).await;
+    // So the frame quality hint is only sent when it changes
+    let mut last_problem = None;
     while let Some(result) = rx.next().await {
         let msg = match result {
             Ok(msg) => msg,
@@ -27,19 +140,34 @@ pub(crate) async fn handle_websocket(websocket: WebSocket) {
         if msg.is_text() {
             if let Ok(text) = msg.to_str() {
                 if let Ok(action_msg) = serde_json::from_str::<ActionMessage>(text) {
-                    handle_action(&action_msg, &mut tx).await;
+                    handle_action(&action_msg, &mut session, &mut tx).await;
                 }
             }
         } else if msg.is_binary() {
-            match image_camera::process_frame(msg.as_bytes()) {
-                Ok(Some(results)) => {
-                    let reply = Message::text(format!(
-                        r#"{{"action": "imageResults", "results": [{}]}}"#,
-                        results
-                    ));
-                    assert!(tx.send(reply).await.is_ok());
+            let Some(database) = open_collection(&session.collection) else {
+                continue;
+            };
+            match image_camera::process_frame(msg.as_bytes(), &database, &session) {
+                Ok(frame) => {
+                    if let Some(results) = frame.results {
+                        let reply = Message::text(format!(
+                            r#"{{"action": "imageResults", "results": [{}]}}"#,
+                            results
+                        ));
+                        assert!(tx.send(reply).await.is_ok());
+                    }
+                    if let Some((key, rejected)) = frame.accepted {
+                        accept_card(&key, rejected, &database, &mut session, &mut tx).await;
+                    }
+                    if frame.problem != last_problem {
+                        last_problem = frame.problem;
+                        let reply = Message::text(format!(
+                            r#"{{"action": "frameQuality", "hint": "{}"}}"#,
+                            frame.problem.map_or("", |problem| problem.hint())
+                        ));
+                        assert!(tx.send(reply).await.is_ok());
+                    }
                 }
-                Ok(None) => (),
                 Err(e) => {
                     eprintln!("{:?}", e);
                 }
@@ -48,17 +176,73 @@ pub(crate) async fn handle_websocket(websocket: WebSocket) {
             println!("Unknown : {:?}", msg);
         }
     }
+    println!("Session {} disconnected", session.id);
+    let mut attached = ATTACHED_SESSIONS.lock().unwrap();
+    attached.remove(&session.id);
+    let mut suspended = SUSPENDED_SESSIONS.lock().unwrap();
+    suspended.retain(|_, (_, suspended_at)| suspended_at.elapsed() < SESSION_KEPT);
+    suspended.insert(session.id.clone(), (session, Instant::now()));
+}
+
+/// Carry on with a suspended session, so its history, set context and binder slot survive a page reload
+/// An id we don't know (the server restarted) is kept so the history still groups the sitting together
+fn resume_session(session_id: Option<&str>, client_id: &str) -> Session {
+    let mut attached = ATTACHED_SESSIONS.lock().unwrap();
+    let session_id = session_id.filter(|id| {
+        !id.is_empty()
+            && id.len() <= 64
+            && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    });
+    let session = match session_id {
+        // A duplicated tab brings its session id along, and a reconnect can beat the old connection closing.
+        // Either way two connections sharing one session would trample each other's state, so start a new one
+        Some(session_id) if attached.contains(session_id) => {
+            println!(
+                "Session {} is already connected, starting a new one",
+                session_id
+            );
+            Session::new(client_id)
+        }
+        Some(session_id) => match SUSPENDED_SESSIONS.lock().unwrap().remove(session_id) {
+            Some((mut session, _)) => {
+                session.client_id = client_id.to_owned();
+                session
+            }
+            None => Session {
+                id: session_id.to_owned(),
+                ..Session::new(client_id)
+            },
+        },
+        None => Session::new(client_id),
+    };
+    attached.insert(session.id.clone());
+    session
 }
 
 async fn handle_action(
     action_msg: &ActionMessage,
+    session: &mut Session,
     tx: &mut (impl SinkExt<Message> + std::marker::Unpin),
 ) {
+    // Actions can name a collection, otherwise they use the session's
+    let collection = action_msg
+        .collection
+        .clone()
+        .unwrap_or_else(|| session.collection.clone());
+    let Some(database) = open_collection(&collection) else {
+        return;
+    };
+
     match action_msg.action.as_str() {
         "search" => {
             if let Some(message) = &action_msg.message {
                 println!("Searching for {}", message);
-                let results = search(message);
+                let results = search(
+                    message,
+                    action_msg.explain.unwrap_or_default(),
+                    &session.pinned_sets,
+                    &database.lock().unwrap(),
+                );
                 let reply = Message::text(format!(
                     r#"{{"action": "searchResults", "results": [{}]}}"#,
                     results
@@ -69,82 +253,378 @@ async fn handle_action(
             }
             return;
         }
+        "expandGroup" => {
+            if let Some(message) = &action_msg.message {
+                let page = action_msg.count.unwrap_or_default();
+                println!("Expanding {} page {}", message, page);
+                let (printings, has_more) =
+                    search::printings_page(message, page, &database.lock().unwrap());
+                let reply = Message::text(format!(
+                    r#"{{"action": "groupPrintings", "oracle_id": "{}", "page": "{}", "has_more": {}, "printings": [{}]}}"#,
+                    message, page, has_more, printings
+                ));
+                assert!(tx.send(reply).await.is_ok());
+            } else {
+                println!("Error getting message.");
+            }
+            return;
+        }
         "history" => {
             println!("Sending history");
-            let (total_cards, total_value, cards) =
-                card_database::CARD_DATABASE.lock().unwrap().history();
+            let filter = match action_msg.message.as_deref() {
+                Some("today") => card_database::HistoryFilter::Today,
+                Some("session") => card_database::HistoryFilter::Session(session.id.clone()),
+                _ => card_database::HistoryFilter::All,
+            };
+            let (totals, cards, summary) = database.lock().unwrap().history(&filter);
             let reply = Message::text(format!(
-                r#"{{"action": "historyResults", "totalCards": "{}", "totalValue": "${:.2}", "cards": [{}]}}"#,
-                total_cards, total_value, cards
+                r#"{{"action": "historyResults", "filter": "{}", "totalCards": "{}", "totalValue": "{}", "unpriced": "{}", "summary": {}, "cards": [{}]}}"#,
+                action_msg.message.as_deref().unwrap_or("all"),
+                totals.cards,
+                PRICING.format(totals.value),
+                totals.unpriced,
+                summary,
+                cards
             ));
             assert!(tx.send(reply).await.is_ok());
             return;
         }
+        "setCompletion" => {
+            if let Some(message) = &action_msg.message {
+                println!("Sending completion of {}", message);
+                let order = action_msg
+                    .sort
+                    .as_deref()
+                    .and_then(ChecklistOrder::from_str)
+                    .unwrap_or(ChecklistOrder::CollectorNumber);
+                let checklist = sets::checklist(
+                    message,
+                    action_msg.checklist_options(),
+                    &database.lock().unwrap(),
+                );
+                match checklist {
+                    Some(checklist) => {
+                        let reply = Message::text(format!(
+                            r#"{{"action": "setCompletion", "completion": {}}}"#,
+                            checklist.to_json(order)
+                        ));
+                        assert!(tx.send(reply).await.is_ok());
+                    }
+                    None => println!("No set {}", message),
+                }
+            } else {
+                println!("Error getting message.");
+            }
+            return;
+        }
+        "tradeables" => {
+            let keep = action_msg
+                .message
+                .as_deref()
+                .and_then(trades::keep_threshold)
+                .unwrap_or(trades::KEEP_COPIES);
+            println!("Sending tradeables keeping {}", keep);
+            let groups = trades::tradeables(&database.lock().unwrap(), keep);
+            let reply = Message::text(format!(
+                r#"{{"action": "tradeables", "keep": "{}", "groups": [{}]}}"#,
+                keep,
+                trades::to_json(&groups)
+            ));
+            assert!(tx.send(reply).await.is_ok());
+            return;
+        }
+        "pinSets" => {
+            if let Some(message) = &action_msg.message {
+                println!("Pinning sets {}", message);
+                search::pin_sets(&mut session.pinned_sets, message);
+                send_set_context(&database, session, tx).await;
+            } else {
+                println!("Error getting message.");
+            }
+            return;
+        }
+        "unpinSet" => {
+            if let Some(message) = &action_msg.message {
+                println!("Unpinning set {}", message);
+                search::unpin_set(&mut session.pinned_sets, message);
+                send_set_context(&database, session, tx).await;
+            } else {
+                println!("Error getting message.");
+            }
+            return;
+        }
+        "collections" => {
+            send_collections(session, tx).await;
+            return;
+        }
+        "setCollection" => {
+            if let Some(message) = action_msg
+                .message
+                .as_deref()
+                .filter(|name| !name.is_empty())
+            {
+                match card_database::collection(message) {
+                    Ok(database) => {
+                        println!("Session {} switched to collection {}", session.id, message);
+                        session.collection = message.to_owned();
+                        // Containers belong to a collection, so the old target doesn't mean anything here
+                        session.target = None;
+                        update_recent(&database, tx).await;
+                        send_set_context(&database, session, tx).await;
+                        send_containers(session, tx).await;
+                    }
+                    Err(e) => eprintln!("Failed to open collection {}: {}", message, e),
+                }
+                send_collections(session, tx).await;
+            } else {
+                println!("Error getting message.");
+            }
+            return;
+        }
+        "transfer" => {
+            if let (Some(key), Some(to)) = (action_msg.key(), &action_msg.to) {
+                let from = action_msg
+                    .collection
+                    .as_deref()
+                    .unwrap_or(&session.collection);
+                match card_database::transfer(
+                    session,
+                    from,
+                    to,
+                    &key,
+                    &action_msg.variant(Finish::NonFoil),
+                    action_msg.count.unwrap_or(1),
+                ) {
+                    Ok(moved) => {
+                        println!("Transferred {} of {} from {} to {}", moved, key, from, to)
+                    }
+                    Err(e) => eprintln!("Failed to transfer {}: {}", key, e),
+                }
+                send_collections(session, tx).await;
+            } else {
+                println!("Error getting message.");
+            }
+        }
+        "containers" => {
+            send_containers(session, tx).await;
+            return;
+        }
+        "setTarget" => {
+            match action_msg
+                .container
+                .as_deref()
+                .filter(|name| !name.is_empty())
+            {
+                Some(name) => {
+                    let kind = action_msg
+                        .kind
+                        .as_deref()
+                        .and_then(ContainerKind::from_str)
+                        .unwrap_or(ContainerKind::Box);
+                    match database.lock().unwrap().create_container(name, kind) {
+                        Ok(container) => {
+                            println!("Scanning into {}", name);
+                            session.target = Some(Target {
+                                container,
+                                position: action_msg.position(),
+                            });
+                        }
+                        Err(e) => eprintln!("Failed to create container {}: {}", name, e),
+                    }
+                }
+                None => {
+                    println!("Scanning into no container");
+                    session.target = None;
+                }
+            }
+            send_containers(session, tx).await;
+            return;
+        }
+        "moveCard" => {
+            if let Some(key) = action_msg.key() {
+                let mut database = database.lock().unwrap();
+                let (from, to) = match (
+                    named_container(&database, &action_msg.from),
+                    named_container(&database, &action_msg.container),
+                ) {
+                    (Ok(from), Ok(to)) => (from, to),
+                    (Err(e), _) | (_, Err(e)) => {
+                        eprintln!("Failed to move {}: {}", key, e);
+                        return;
+                    }
+                };
+                let describe = |container: &Option<Container>| {
+                    container
+                        .as_ref()
+                        .map_or("unsorted".to_owned(), |container| container.name.clone())
+                };
+                let (from_name, to_name) = (describe(&from), describe(&to));
+                let to = to.map(|container| Target {
+                    container,
+                    position: action_msg.position(),
+                });
+                match database.move_copies(
+                    &key,
+                    &action_msg.variant(Finish::NonFoil),
+                    from.as_ref(),
+                    to.as_ref(),
+                    action_msg.count.unwrap_or(1),
+                ) {
+                    Ok(moved) => {
+                        println!(
+                            "Moved {} of {} from {} to {}",
+                            moved, key, from_name, to_name
+                        )
+                    }
+                    Err(e) => eprintln!("Failed to move {}: {}", key, e),
+                }
+            } else {
+                println!("Error getting message.");
+            }
+        }
         "reject" => {
             println!("Reject");
-            // By marking a card as dead, the next frame it's detected it'll recalculate what the card is
-            kill_card();
+            // The card stays tracked and its next candidates are shown straight away, it's only read again
+            // (with different preprocessing) once they've all been rejected
+            let candidates = {
+                let Ok(mut card) = image_camera::CARD.lock() else {
+                    return;
+                };
+                if card.tracker.reject() {
+                    card.tracker.candidates().to_vec()
+                } else {
+                    card.processed = false;
+                    vec![]
+                }
+            };
+
+            let reply = Message::text(format!(
+                r#"{{"action": "imageResults", "results": [{}]}}"#,
+                search::results_json(&candidates, false, &database.lock().unwrap())
+            ));
+            assert!(tx.send(reply).await.is_ok());
+            return;
+        }
+        "autoAccept" => {
+            session.auto_accept = action_msg.message.as_deref() == Some("on");
+            println!(
+                "Auto accept {} for session {}",
+                if session.auto_accept { "on" } else { "off" },
+                session.id
+            );
+            send_auto_accept(session, tx).await;
             return;
         }
+        "undoAccept" => {
+            // Only the card that was just auto accepted, and only for a little while
+            match session.accepted.take() {
+                Some(accepted) if accepted.at.elapsed().as_secs() < AUTO_ACCEPT.undo_seconds => {
+                    println!("Undoing auto accept of {}", accepted.key);
+                    let undone = open_collection(&accepted.collection)
+                        .map(|database| database.lock().unwrap().take_back(accepted.entry));
+                    match undone {
+                        Some(Ok(Some(_))) => {
+                            // Give the binder slot it took back, unless the target has been changed since
+                            let same_target =
+                                session.target.as_ref().map(|target| target.container.id)
+                                    == accepted.target.as_ref().map(|target| target.container.id);
+                            if same_target {
+                                session.target = accepted.target;
+                            }
+                            // Undoing says it was the wrong card, so it mustn't come up for it again. It has left the
+                            // frame, so if nothing's been put down since, the next card gets the blacklist. If
+                            // something has, it's most likely the same card back, read it again without them
+                            if let Ok(mut card) = image_camera::CARD.lock() {
+                                if card.alive {
+                                    card.tracker.blacklist(accepted.rejected);
+                                    card.processed = false;
+                                } else {
+                                    card.tracker.carry(accepted.rejected);
+                                }
+                            }
+                        }
+                        Some(Ok(None)) => {
+                            println!("{} has changed since, not undoing", accepted.key)
+                        }
+                        Some(Err(e)) => eprintln!("Failed to undo: {}", e),
+                        None => (),
+                    }
+                }
+                _ => println!("Nothing to undo."),
+            }
+        }
         "incCard" => {
-            if let Some(message) = &action_msg.message {
-                println!("Incrementing {}", message);
-                card_database::CARD_DATABASE
+            if let Some(key) = action_msg.key() {
+                println!("Incrementing {}", key);
+                database
                     .lock()
                     .unwrap()
-                    .inc(message, false);
+                    .inc(session, &key, &action_msg.variant(Finish::NonFoil));
+                session.advance_target();
                 kill_card();
             } else {
                 println!("Error getting message.");
             }
         }
         "decCard" => {
-            if let Some(message) = &action_msg.message {
-                println!("Decrementing {}", message);
-                card_database::CARD_DATABASE
+            if let Some(key) = action_msg.key() {
+                println!("Decrementing {}", key);
+                database
                     .lock()
                     .unwrap()
-                    .dec(message, false);
+                    .dec(session, &key, &action_msg.variant(Finish::NonFoil));
                 kill_card();
             } else {
                 println!("Error getting message.");
             }
         }
         "incFoil" => {
-            if let Some(message) = &action_msg.message {
-                println!("Incrementing foil {}", message);
-                card_database::CARD_DATABASE
+            if let Some(key) = action_msg.key() {
+                println!("Incrementing foil {}", key);
+                database
                     .lock()
                     .unwrap()
-                    .inc(message, true);
+                    .inc(session, &key, &action_msg.variant(Finish::Foil));
+                session.advance_target();
                 kill_card();
             } else {
                 println!("Error getting message.");
             }
         }
         "decFoil" => {
-            if let Some(message) = &action_msg.message {
-                println!("Decrementing foil {}", message);
-                card_database::CARD_DATABASE
+            if let Some(key) = action_msg.key() {
+                println!("Decrementing foil {}", key);
+                database
                     .lock()
                     .unwrap()
-                    .dec(message, true);
+                    .dec(session, &key, &action_msg.variant(Finish::Foil));
                 kill_card();
             } else {
                 println!("Error getting message.");
             }
         }
+        "undo" => match card_database::undo(&collection) {
+            Ok(Some(entry)) => println!("Undid {:?} of {}", entry.change_type, entry.key),
+            Ok(None) => println!("Nothing to undo"),
+            Err(e) => eprintln!("Failed to undo: {}", e),
+        },
+        "redo" => match card_database::redo(&collection) {
+            Ok(Some(entry)) => println!("Redid {:?} of {}", entry.change_type, entry.key),
+            Ok(None) => println!("Nothing to redo"),
+            Err(e) => eprintln!("Failed to redo: {}", e),
+        },
         "setCard" => {
-            if let Some(message) = &action_msg.message {
+            if let Some(key) = action_msg.key() {
                 println!(
                     "Setting {} count to {}",
-                    message,
+                    key,
                     action_msg.count.unwrap_or_default()
                 );
-                card_database::CARD_DATABASE.lock().unwrap().set(
-                    message,
+                database.lock().unwrap().set(
+                    session,
+                    &key,
                     action_msg.count.unwrap_or_default(),
-                    false,
+                    &action_msg.variant(Finish::NonFoil),
                 );
             } else {
                 println!(
@@ -154,16 +634,17 @@ async fn handle_action(
             }
         }
         "setFoil" => {
-            if let Some(message) = &action_msg.message {
+            if let Some(key) = action_msg.key() {
                 println!(
                     "Setting foil {} count to {}",
-                    message,
+                    key,
                     action_msg.count.unwrap_or_default()
                 );
-                card_database::CARD_DATABASE.lock().unwrap().set(
-                    message,
+                database.lock().unwrap().set(
+                    session,
+                    &key,
                     action_msg.count.unwrap_or_default(),
-                    true,
+                    &action_msg.variant(Finish::Foil),
                 );
             } else {
                 println!(
@@ -177,24 +658,192 @@ async fn handle_action(
             return;
         }
     }
-    update_recent(tx).await;
+    update_recent(&database, tx).await;
+    send_set_context(&database, session, tx).await;
+    send_containers(session, tx).await;
+}
+
+/// The container an action names, where leaving it out (or empty) means unsorted
+/// A name that doesn't match a container is an error rather than unsorted, so a typo can't move the wrong copies
+fn named_container(database: &CardDatabase, name: &Option<String>) -> Result<Option<Container>> {
+    match name.as_deref().filter(|name| !name.is_empty()) {
+        None => Ok(None),
+        Some(name) => match database.container(name)? {
+            Some(container) => Ok(Some(container)),
+            None => bail!("there's no container named {}", name),
+        },
+    }
+}
+
+/// An existing collection's database, logging why if it can't be opened
+/// Only `setCollection` creates collections, a name anywhere else is most likely a typo or out of date
+fn open_collection(name: &str) -> Option<Arc<Mutex<CardDatabase>>> {
+    match card_database::existing_collection(name) {
+        Ok(Some(database)) => Some(database),
+        Ok(None) => {
+            eprintln!("There's no collection named {}", name);
+            None
+        }
+        Err(e) => {
+            eprintln!("Failed to open collection {}: {}", name, e);
+            None
+        }
+    }
+}
+
+/// Every collection with its totals, plus the totals across all of them
+async fn send_collections(
+    session: &Session,
+    tx: &mut (impl SinkExt<Message> + std::marker::Unpin),
+) {
+    let totals: Vec<(String, Totals)> = card_database::collection_names()
+        .into_iter()
+        .filter_map(|name| {
+            let totals = open_collection(&name)?.lock().unwrap().totals();
+            Some((name, totals))
+        })
+        .collect();
+    let collections = totals
+        .iter()
+        .map(|(name, totals)| {
+            format!(
+                r#"{{"name": "{}", "totalCards": "{}", "totalValue": "{}", "unpriced": "{}"}}"#,
+                name,
+                totals.cards,
+                PRICING.format(totals.value),
+                totals.unpriced
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    let reply = Message::text(format!(
+        r#"{{"action": "collections", "current": "{}", "collections": [{}], "totalCards": "{}", "totalValue": "{}", "unpriced": "{}"}}"#,
+        session.collection,
+        collections,
+        totals.iter().map(|(_, totals)| totals.cards).sum::<usize>(),
+        PRICING.format(totals.iter().map(|(_, totals)| totals.value).sum::<f64>()),
+        totals
+            .iter()
+            .map(|(_, totals)| totals.unpriced)
+            .sum::<usize>()
+    ));
+    assert!(tx.send(reply).await.is_ok());
 }
 
-async fn update_recent(tx: &mut (impl SinkExt<Message> + std::marker::Unpin)) {
+/// Every container, and where this session is putting new copies
+async fn send_containers(session: &Session, tx: &mut (impl SinkExt<Message> + std::marker::Unpin)) {
+    let Some(database) = open_collection(&session.collection) else {
+        return;
+    };
+    let containers = database
+        .lock()
+        .unwrap()
+        .containers()
+        .iter()
+        .map(|(container, count)| {
+            format!(
+                r#"{{"name": {}, "kind": "{}", "count": "{}"}}"#,
+                serde_json::to_string(&container.name).unwrap(),
+                container.kind.as_str(),
+                count
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    let reply = Message::text(format!(
+        r#"{{"action": "containers", "containers": [{}], "target": {}}}"#,
+        containers,
+        session
+            .target
+            .as_ref()
+            .map_or("null".to_owned(), Target::to_json)
+    ));
+    assert!(tx.send(reply).await.is_ok());
+}
+
+/// Add a card that was auto accepted, and tell the UI so it can offer to undo it
+async fn accept_card(
+    key: &PrintingKey,
+    rejected: HashSet<String>,
+    database: &Mutex<CardDatabase>,
+    session: &mut Session,
+    tx: &mut (impl SinkExt<Message> + std::marker::Unpin),
+) {
+    println!("Auto accepting {}", key);
+    let entry = database
+        .lock()
+        .unwrap()
+        .inc(session, key, &Variant::new(Finish::NonFoil));
+    let target = session.target.clone();
+    session.advance_target();
+    session.accepted = entry.map(|entry| Accepted {
+        key: key.clone(),
+        collection: session.collection.clone(),
+        entry,
+        target,
+        rejected,
+        at: Instant::now(),
+    });
+
+    let cards = card_store::cards();
+    let reply = Message::text(format!(
+        r#"{{"action": "autoAccepted", "uuid": "{}", "name": {}, "undoSeconds": "{}"}}"#,
+        cards.image(key).unwrap_or_default(),
+        serde_json::to_string(
+            cards
+                .get_card_by_id(&key.scryfall_id)
+                .map_or("", |card| card.name())
+        )
+        .unwrap(),
+        AUTO_ACCEPT.undo_seconds
+    ));
+    assert!(tx.send(reply).await.is_ok());
+    update_recent(database, tx).await;
+}
+
+async fn send_auto_accept(
+    session: &Session,
+    tx: &mut (impl SinkExt<Message> + std::marker::Unpin),
+) {
+    let reply = Message::text(format!(
+        r#"{{"action": "autoAccept", "enabled": {}}}"#,
+        session.auto_accept
+    ));
+    assert!(tx.send(reply).await.is_ok());
+}
+
+async fn send_set_context(
+    database: &Mutex<CardDatabase>,
+    session: &Session,
+    tx: &mut (impl SinkExt<Message> + std::marker::Unpin),
+) {
+    let reply = Message::text(format!(
+        r#"{{"action": "setContext", "context": {}}}"#,
+        search::set_context(&session.pinned_sets, &database.lock().unwrap())
+    ));
+    assert!(tx.send(reply).await.is_ok());
+}
+
+async fn update_recent(
+    database: &Mutex<CardDatabase>,
+    tx: &mut (impl SinkExt<Message> + std::marker::Unpin),
+) {
     if let Some(card) = {
-        let scryrs = crate::search::CARDS.lock().unwrap();
-        let database = card_database::CARD_DATABASE.lock().unwrap();
-        database.history
-                .iter().next_back()
-                .and_then(|history_entry| {
-                    format!(
-                        r#"{{"uuid": "{}", "non_foil_count": "{}", "foil_count": "{}", "value": "{:.2}"}}"#,
-                        &history_entry.file_name,
-                        database.get(&history_entry.file_name),
-                        database.get_foil(&history_entry.file_name),
-                        scryrs.get_card_by_id(&history_entry.file_name[..history_entry.file_name.rfind('-').unwrap()]).unwrap().usd()
-                    ).into()
-                })
+        let scryrs = card_store::cards();
+        let database = database.lock().unwrap();
+        database
+            .recent_history(1)
+            .first()
+            .and_then(|history_entry| {
+                let card = scryrs.get_card_by_id(&history_entry.key.scryfall_id)?;
+                format!(
+                    r#"{{"uuid": "{}", {}, {}}}"#,
+                    scryrs.image(&history_entry.key).unwrap_or_default(),
+                    database.counts_json(&history_entry.key, card),
+                    PRICING.json(PRICING.card_price(card, Finish::NonFoil))
+                )
+                .into()
+            })
     } {
         let reply = Message::text(format!(
             r#"{{"action": "update_recent", "card": {}}}"#,